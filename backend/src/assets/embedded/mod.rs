@@ -0,0 +1,75 @@
+use warp::http::{StatusCode, header};
+use warp::reply::Response;
+
+/// The built frontend, compiled into the binary
+#[derive(rust_embed::Embed)]
+#[folder = "../frontend/"]
+#[include = "index.html"]
+#[include = "main.js"]
+#[include = "main.css"]
+#[include = "images/*"]
+struct Frontend;
+
+/// Respond with the embedded file at `path`. Browsers that already have the
+/// same version, going by `if_none_match`, are told to use their copy.
+pub fn serve(path: &str, if_none_match: Option<&str>) -> Response {
+    let Some(file) = Frontend::get(path) else {
+        return status(StatusCode::NOT_FOUND);
+    };
+    let etag = etag(&file.metadata.sha256_hash());
+    if if_none_match.is_some_and(|tags| tags.split(',').any(|tag| tag.trim() == etag)) {
+        let mut response = status(StatusCode::NOT_MODIFIED);
+        insert_header(&mut response, header::ETAG, &etag);
+        return response;
+    }
+    let mut response = Response::new(file.data.into_owned().into());
+    insert_header(
+        &mut response,
+        header::CONTENT_TYPE,
+        file.metadata.mimetype(),
+    );
+    insert_header(&mut response, header::ETAG, &etag);
+    response
+}
+
+fn status(code: StatusCode) -> Response {
+    let mut response = Response::default();
+    *response.status_mut() = code;
+    response
+}
+
+fn insert_header(response: &mut Response, name: header::HeaderName, value: &str) {
+    if let Ok(value) = header::HeaderValue::from_str(value) {
+        response.headers_mut().insert(name, value);
+    }
+}
+
+/// A strong ETag made from the file's hash, so it only changes when the file
+/// does
+fn etag(hash: &[u8]) -> String {
+    let hex: String = hash.iter().map(|byte| format!("{:02x}", byte)).collect();
+    format!("\"{}\"", hex)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn serves_files_with_etags() {
+        let response = serve("index.html", None);
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[header::CONTENT_TYPE], "text/html");
+        let etag = response.headers()[header::ETAG]
+            .to_str()
+            .unwrap()
+            .to_string();
+
+        let response = serve("index.html", Some(&etag));
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+        let response = serve("main.css", Some(&etag));
+        assert_eq!(response.status(), StatusCode::OK);
+
+        assert_eq!(serve("elm.json", None).status(), StatusCode::NOT_FOUND);
+    }
+}
@@ -0,0 +1,68 @@
+use std::path::Path;
+
+use warp::Filter;
+use warp::filters::BoxedFilter;
+use warp::reply::Response;
+
+#[cfg(feature = "embed")]
+mod embedded;
+
+/// Routes for the frontend: The page itself, its script and styles, and the
+/// card images.
+#[cfg(not(feature = "embed"))]
+pub fn routes(static_dir: &Path) -> BoxedFilter<(Response,)> {
+    let index = warp::path::end().and(warp::fs::file(static_dir.join("index.html")));
+    let index_js = warp::path("main.js").and(warp::fs::file(static_dir.join("main.js")));
+    let index_css = warp::path("main.css").and(warp::fs::file(static_dir.join("main.css")));
+    let images = warp::path("snap")
+        .and(warp::path("images"))
+        .and(warp::fs::dir(static_dir.join("images")));
+    index
+        .or(index_js)
+        .unify()
+        .or(index_css)
+        .unify()
+        .or(images)
+        .unify()
+        .map(warp::Reply::into_response)
+        .boxed()
+}
+
+/// Routes for the frontend, served from the copy built into the binary. The
+/// static asset directory isn't used.
+#[cfg(feature = "embed")]
+pub fn routes(_static_dir: &Path) -> BoxedFilter<(Response,)> {
+    let asset = |path: &'static str| {
+        warp::get()
+            .and(warp::header::optional::<String>("if-none-match"))
+            .map(move |if_none_match: Option<String>| {
+                embedded::serve(path, if_none_match.as_deref())
+            })
+    };
+    let index = warp::path::end().and(asset("index.html"));
+    let index_js = warp::path("main.js")
+        .and(warp::path::end())
+        .and(asset("main.js"));
+    let index_css = warp::path("main.css")
+        .and(warp::path::end())
+        .and(asset("main.css"));
+    let images = warp::path("snap")
+        .and(warp::path("images"))
+        .and(warp::get())
+        .and(warp::path::tail())
+        .and(warp::header::optional::<String>("if-none-match"))
+        .map(|tail: warp::path::Tail, if_none_match: Option<String>| {
+            embedded::serve(
+                &format!("images/{}", tail.as_str()),
+                if_none_match.as_deref(),
+            )
+        });
+    index
+        .or(index_js)
+        .unify()
+        .or(index_css)
+        .unify()
+        .or(images)
+        .unify()
+        .boxed()
+}
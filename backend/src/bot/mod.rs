@@ -0,0 +1,368 @@
+use std::time::{Duration, Instant};
+
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc;
+
+use crate::game::{GameState, InputMessageType, OutputMessageType, PlayerNumber};
+
+/// How good a bot is at snap
+#[derive(Copy, Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Difficulty {
+    Easy,
+    Medium,
+    Hard,
+}
+
+impl Difficulty {
+    /// Name the bot plays under. Player names can't have brackets, so nobody
+    /// can pass themselves off as a bot.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Difficulty::Easy => "Bot (easy)",
+            Difficulty::Medium => "Bot (medium)",
+            Difficulty::Hard => "Bot (hard)",
+        }
+    }
+
+    pub fn config(&self) -> BotConfig {
+        match self {
+            Difficulty::Easy => BotConfig {
+                reaction_time: Duration::from_millis(900),
+                reaction_spread: Duration::from_millis(300),
+                draw_time: Duration::from_millis(900),
+                false_snap_chance: 0.08,
+                miss_chance: 0.2,
+            },
+            Difficulty::Medium => BotConfig {
+                reaction_time: Duration::from_millis(600),
+                reaction_spread: Duration::from_millis(200),
+                draw_time: Duration::from_millis(700),
+                false_snap_chance: 0.04,
+                miss_chance: 0.08,
+            },
+            Difficulty::Hard => BotConfig {
+                reaction_time: Duration::from_millis(350),
+                reaction_spread: Duration::from_millis(100),
+                draw_time: Duration::from_millis(500),
+                false_snap_chance: 0.01,
+                miss_chance: 0.02,
+            },
+        }
+    }
+}
+
+/// How a bot plays. Reaction and draw times are bell-shaped around their
+/// average, within `reaction_spread` either side.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct BotConfig {
+    /// Average time to snap once the cards match
+    pub reaction_time: Duration,
+    pub reaction_spread: Duration,
+    /// Average time to draw once it's the bot's turn
+    pub draw_time: Duration,
+    /// Chance of snapping a card that doesn't match
+    pub false_snap_chance: f64,
+    /// Chance of not noticing a snap at all
+    pub miss_chance: f64,
+}
+
+/// What a bot decides to do about something that happened in the game
+#[derive(Debug, PartialEq)]
+enum Reaction {
+    /// Carry on with whatever was planned
+    Wait,
+    /// Drop whatever was planned
+    Forget,
+    /// Send a message after thinking about it
+    Send {
+        after: Duration,
+        message: InputMessageType,
+    },
+}
+
+/// Decides how a bot responds to the game. It sees the same messages players
+/// do, plus the table they'd see on screen.
+pub struct Bot {
+    player: PlayerNumber,
+    config: BotConfig,
+    rng: StdRng,
+}
+
+impl Bot {
+    pub fn new(player: PlayerNumber, config: BotConfig) -> Self {
+        Self {
+            player,
+            config,
+            rng: StdRng::from_rng(&mut rand::rng()),
+        }
+    }
+
+    /// Respond to `event`, or to the table changing without one (e.g. the game
+    /// resuming)
+    fn react(&mut self, event: Option<&OutputMessageType>, table: &GameState) -> Reaction {
+        if table.winner.is_some() {
+            return Reaction::Forget;
+        }
+        let awaiting_response =
+            table.awaiting_responses && table.pending_responses[self.player].is_none();
+        match event {
+            Some(OutputMessageType::CardDrawn { .. }) => {
+                if awaiting_response {
+                    if self.rng.random_bool(self.config.miss_chance) {
+                        return Reaction::Forget;
+                    }
+                    return self.snap();
+                }
+                if self.rng.random_bool(self.config.false_snap_chance) {
+                    return self.snap();
+                }
+                self.draw_if_my_turn(table)
+            }
+            Some(OutputMessageType::DrawTooSoon { wait_ms }) => Reaction::Send {
+                after: Duration::from_millis((*wait_ms).into()),
+                message: InputMessageType::Draw(*wait_ms),
+            },
+            // The last response can close the window without anything else
+            // happening, if nobody snapped in time
+            Some(OutputMessageType::OtherPlayerResponded { .. }) if !table.awaiting_responses => {
+                self.draw_if_my_turn(table)
+            }
+            Some(OutputMessageType::OtherPlayerResponded { .. })
+            | Some(OutputMessageType::Rejected(_)) => Reaction::Wait,
+            _ if awaiting_response => Reaction::Wait,
+            _ => self.draw_if_my_turn(table),
+        }
+    }
+
+    fn snap(&mut self) -> Reaction {
+        let after = self.think(self.config.reaction_time);
+        Reaction::Send {
+            after,
+            message: InputMessageType::Snap(millis(after)),
+        }
+    }
+
+    fn draw_if_my_turn(&mut self, table: &GameState) -> Reaction {
+        if table.player_turn != self.player || table.awaiting_responses {
+            return Reaction::Forget;
+        }
+        let after = self.think(self.config.draw_time);
+        Reaction::Send {
+            after,
+            message: InputMessageType::Draw(millis(after)),
+        }
+    }
+
+    /// Time taken to do something that takes `average` time. Averaging a few
+    /// uniform samples gives a rough bell curve.
+    fn think(&mut self, average: Duration) -> Duration {
+        let spread = self.config.reaction_spread;
+        let shape: f64 = (0..3).map(|_| self.rng.random::<f64>()).sum::<f64>() / 3.0;
+        (average + spread.mul_f64(2.0 * shape)).saturating_sub(spread)
+    }
+}
+
+fn millis(duration: Duration) -> u32 {
+    duration.as_millis().try_into().unwrap_or(u32::MAX)
+}
+
+/// Runs a bot in its own task. Pass it messages like you would a websocket.
+pub struct BotHandler {
+    send_channel: mpsc::UnboundedSender<Option<OutputMessageType>>,
+    cancellation_token: tokio_util::sync::CancellationToken,
+}
+
+impl BotHandler {
+    /// Start the bot. It sends messages with `on_message`, and uses `look` to
+    /// see the table, which returns `None` once the game is gone. The bot runs
+    /// until `.close()` is called.
+    pub fn new<EmptyFuture, TableFuture>(
+        mut bot: Bot,
+        mut on_message: impl FnMut(InputMessageType) -> EmptyFuture + Send + 'static,
+        mut look: impl FnMut() -> TableFuture + Send + 'static,
+    ) -> Self
+    where
+        EmptyFuture: Future<Output = ()> + Send,
+        TableFuture: Future<Output = Option<GameState>> + Send,
+    {
+        let (send_channel, mut receive_channel) =
+            mpsc::unbounded_channel::<Option<OutputMessageType>>();
+        let cancellation_token = tokio_util::sync::CancellationToken::new();
+        {
+            let cancellation_token = cancellation_token.clone();
+            tokio::task::spawn(async move {
+                let mut planned: Option<(Instant, InputMessageType)> = None;
+                loop {
+                    let wake_at = planned.map_or_else(Instant::now, |(at, _)| at);
+                    tokio::select! {
+                        biased;
+                        _ = cancellation_token.cancelled() => break,
+                        event = receive_channel.recv() => {
+                            let Some(event) = event else {
+                                break;
+                            };
+                            let Some(table) = look().await else {
+                                break;
+                            };
+                            match bot.react(event.as_ref(), &table) {
+                                Reaction::Wait => {}
+                                Reaction::Forget => planned = None,
+                                Reaction::Send { after, message } => {
+                                    planned = Some((Instant::now() + after, message));
+                                }
+                            }
+                        }
+                        _ = tokio::time::sleep_until(wake_at.into()), if planned.is_some() => {
+                            if let Some((_, message)) = planned.take() {
+                                on_message(message).await;
+                            }
+                        }
+                    }
+                }
+            });
+        }
+        BotHandler {
+            send_channel,
+            cancellation_token,
+        }
+    }
+
+    pub fn send(&self, message: OutputMessageType) -> Result<(), ()> {
+        self.send_channel.send(Some(message)).map_err(|_| ())
+    }
+
+    /// Get the bot to look at the table again, e.g. because the game resumed
+    pub fn nudge(&self) {
+        _ = self.send_channel.send(None);
+    }
+
+    /// Bots live on the server, so their messages arrive instantly
+    pub fn round_trip(&self) -> Option<Duration> {
+        Some(Duration::ZERO)
+    }
+
+    pub fn close(&self) {
+        self.cancellation_token.cancel();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::game::cards::{Card, Suit, Value};
+
+    fn table(player_turn: PlayerNumber, awaiting_responses: bool) -> GameState {
+        GameState {
+            hand_sizes: vec![10, 10],
+            center_pile: vec![],
+            player_turn,
+            pending_responses: vec![None, None],
+            awaiting_responses,
+            winner: None,
+        }
+    }
+
+    fn card_drawn() -> OutputMessageType {
+        OutputMessageType::CardDrawn {
+            card: Card {
+                suit: Suit::Clubs,
+                value: Value::Two,
+            },
+            from: 0,
+        }
+    }
+
+    fn careful_bot() -> Bot {
+        Bot::new(
+            1,
+            BotConfig {
+                false_snap_chance: 0.0,
+                miss_chance: 0.0,
+                ..Difficulty::Medium.config()
+            },
+        )
+    }
+
+    #[test]
+    fn bot_snaps_matching_cards() {
+        let mut bot = careful_bot();
+        let reaction = bot.react(Some(&card_drawn()), &table(1, true));
+        let Reaction::Send {
+            after,
+            message: InputMessageType::Snap(ms),
+        } = reaction
+        else {
+            panic!("Expected a snap, got {:?}", reaction);
+        };
+        assert_eq!(after.as_millis(), ms.into());
+        let config = Difficulty::Medium.config();
+        assert!(after >= config.reaction_time - config.reaction_spread);
+        assert!(after <= config.reaction_time + config.reaction_spread);
+    }
+
+    #[test]
+    fn bot_draws_on_its_turn() {
+        let mut bot = careful_bot();
+        assert!(matches!(
+            bot.react(Some(&card_drawn()), &table(1, false)),
+            Reaction::Send {
+                message: InputMessageType::Draw(_),
+                ..
+            }
+        ));
+        assert_eq!(
+            bot.react(Some(&card_drawn()), &table(0, false)),
+            Reaction::Forget
+        );
+    }
+
+    #[test]
+    fn bot_draws_after_missed_snap() {
+        let mut bot = Bot::new(
+            1,
+            BotConfig {
+                false_snap_chance: 0.0,
+                miss_chance: 1.0,
+                ..Difficulty::Easy.config()
+            },
+        );
+        assert_eq!(
+            bot.react(Some(&card_drawn()), &table(1, true)),
+            Reaction::Forget
+        );
+        // The window closes with nobody having snapped
+        let no_response = OutputMessageType::OtherPlayerResponded {
+            player: 1,
+            msg: InputMessageType::NoResponse,
+            is_mistake: false,
+        };
+        assert!(matches!(
+            bot.react(Some(&no_response), &table(1, false)),
+            Reaction::Send {
+                message: InputMessageType::Draw(_),
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn bot_false_snaps() {
+        let mut bot = Bot::new(
+            1,
+            BotConfig {
+                false_snap_chance: 1.0,
+                ..Difficulty::Easy.config()
+            },
+        );
+        assert!(matches!(
+            bot.react(Some(&card_drawn()), &table(0, false)),
+            Reaction::Send {
+                message: InputMessageType::Snap(_),
+                ..
+            }
+        ));
+    }
+}
@@ -0,0 +1,420 @@
+use std::net::{IpAddr, Ipv4Addr};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use clap::{Parser, Subcommand, ValueEnum};
+use serde::Deserialize;
+use serde::de::IntoDeserializer;
+
+use crate::{game, websocket};
+
+const DEFAULT_PORT: u16 = 3030;
+const DEFAULT_MAX_GAMES: usize = 1000;
+const DEFAULT_STATIC_DIR: &str = "../frontend";
+const DEFAULT_DATABASE: &str = "snap.db";
+const DEFAULT_GAME_LOG_DIR: &str = "game-logs";
+const DEFAULT_RECONNECT_GRACE_SECS: u64 = 30;
+const DEFAULT_QUICKPLAY_TIMEOUT_SECS: u64 = 120;
+const DEFAULT_QUICKPLAY_BOT_AFTER_SECS: u64 = 20;
+const DEFAULT_SHUTDOWN_DRAIN_SECS: u64 = 20;
+const DEFAULT_PING_INTERVAL_MS: u64 = millis(websocket::DEFAULT_PING_INTERVAL);
+const DEFAULT_PONG_TIMEOUT_MS: u64 = millis(websocket::DEFAULT_PONG_TIMEOUT);
+const DEFAULT_OVERFLOW_GRACE_MS: u64 = 5000;
+
+/// Command line for the server. Every setting can also be given through an
+/// environment variable or a config file. Flags win over environment
+/// variables, which win over the file.
+#[derive(Debug, Parser)]
+#[command(version, about = "Server for the snap card game")]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+    /// TOML file to read settings from
+    #[arg(long, env = "SNAP_CONFIG")]
+    config: Option<PathBuf>,
+    #[arg(long, env = "SNAP_BIND_ADDRESS")]
+    bind_address: Option<IpAddr>,
+    #[arg(long, env = "SNAP_PORT")]
+    port: Option<u16>,
+    /// Most games that can be running at once
+    #[arg(long, env = "SNAP_MAX_GAMES")]
+    max_games: Option<usize>,
+    /// Directory holding the built frontend. Not used when the frontend is
+    /// embedded in the binary.
+    #[arg(long, env = "SNAP_STATIC_DIR")]
+    static_dir: Option<PathBuf>,
+    /// SQLite database for player profiles
+    #[arg(long, env = "SNAP_DATABASE")]
+    database: Option<PathBuf>,
+    /// Directory to keep logs of finished games in, for `replay`
+    #[arg(long, env = "SNAP_GAME_LOG_DIR")]
+    game_log_dir: Option<PathBuf>,
+    #[arg(long, env = "SNAP_RECONNECT_GRACE_SECS")]
+    reconnect_grace_secs: Option<u64>,
+    #[arg(long, env = "SNAP_QUICKPLAY_TIMEOUT_SECS")]
+    quickplay_timeout_secs: Option<u64>,
+    /// Give quickplay players a bot after this long; 0 never does
+    #[arg(long, env = "SNAP_QUICKPLAY_BOT_AFTER_SECS")]
+    quickplay_bot_after_secs: Option<u64>,
+    /// Time running games get to finish when the server is stopped
+    #[arg(long, env = "SNAP_SHUTDOWN_DRAIN_SECS")]
+    shutdown_drain_secs: Option<u64>,
+    /// How often connections are pinged to check they're still alive
+    #[arg(long, env = "SNAP_PING_INTERVAL_MS")]
+    ping_interval_ms: Option<u64>,
+    /// Disconnect clients that haven't answered for this long
+    #[arg(long, env = "SNAP_PONG_TIMEOUT_MS")]
+    pong_timeout_ms: Option<u64>,
+    /// What to do when a player falls too far behind with their messages
+    #[arg(long, env = "SNAP_OVERFLOW")]
+    overflow: Option<Overflow>,
+    /// How long a player can stay too far behind with `--overflow grace`
+    #[arg(long, env = "SNAP_OVERFLOW_GRACE_MS")]
+    overflow_grace_ms: Option<u64>,
+    /// Seats at a game, unless its creator chooses otherwise
+    #[arg(long, env = "SNAP_PLAYERS")]
+    players: Option<usize>,
+    /// Rounds in a match, unless its creator chooses otherwise
+    #[arg(long, env = "SNAP_BEST_OF")]
+    best_of: Option<usize>,
+    #[arg(long, env = "SNAP_DRAW_COOLDOWN_MS")]
+    draw_cooldown_ms: Option<u64>,
+    #[arg(long, env = "SNAP_RESPONSE_WINDOW_MS")]
+    response_window_ms: Option<u64>,
+    /// Snap rules to play by, unless a game's creator chooses otherwise
+    #[arg(long, env = "SNAP_RULES", value_delimiter = ',')]
+    rules: Option<Vec<Rule>>,
+    /// Also snap whenever this card is on top, named as in the config file
+    /// (`Queen`, `Ace`, ...)
+    #[arg(long, env = "SNAP_NAMED_CARD", value_parser = parse_card_value)]
+    named_card: Option<game::cards::Value>,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Check a game log written by the server still plays out the same way,
+    /// instead of running the server
+    Replay { log: PathBuf },
+}
+
+/// Snap rules that can be switched on from the command line
+#[derive(Copy, Clone, Debug, ValueEnum)]
+enum Rule {
+    Pairs,
+    Sandwich,
+    SameSuit,
+    SumToTen,
+}
+
+/// What to do with players that fall too far behind; see
+/// `websocket::OverflowPolicy`
+#[derive(Copy, Clone, Debug, PartialEq, Deserialize, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum Overflow {
+    /// Give them `overflow_grace_ms` to catch up, then disconnect them
+    Grace,
+    /// Disconnect them straight away
+    Disconnect,
+    /// Drop what they haven't been sent, and send them the whole table.
+    /// Clients need to understand `GameState` to catch up this way.
+    Resync,
+}
+
+/// Everything that can be set without rebuilding the server
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub bind_address: IpAddr,
+    pub port: u16,
+    pub max_games: usize,
+    pub static_dir: PathBuf,
+    pub database: PathBuf,
+    pub game_log_dir: PathBuf,
+    pub reconnect_grace_secs: u64,
+    pub quickplay_timeout_secs: u64,
+    pub quickplay_bot_after_secs: u64,
+    pub shutdown_drain_secs: u64,
+    pub ping_interval_ms: u64,
+    pub pong_timeout_ms: u64,
+    pub overflow: Overflow,
+    pub overflow_grace_ms: u64,
+    pub game: GameDefaults,
+}
+
+/// Settings for new games, before any options chosen by the creator
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct GameDefaults {
+    pub players: usize,
+    pub best_of: usize,
+    pub draw_cooldown_ms: u64,
+    pub response_window_ms: u64,
+    pub rules: game::SnapRules,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            bind_address: IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            port: DEFAULT_PORT,
+            max_games: DEFAULT_MAX_GAMES,
+            static_dir: PathBuf::from(DEFAULT_STATIC_DIR),
+            database: PathBuf::from(DEFAULT_DATABASE),
+            game_log_dir: PathBuf::from(DEFAULT_GAME_LOG_DIR),
+            reconnect_grace_secs: DEFAULT_RECONNECT_GRACE_SECS,
+            quickplay_timeout_secs: DEFAULT_QUICKPLAY_TIMEOUT_SECS,
+            quickplay_bot_after_secs: DEFAULT_QUICKPLAY_BOT_AFTER_SECS,
+            shutdown_drain_secs: DEFAULT_SHUTDOWN_DRAIN_SECS,
+            ping_interval_ms: DEFAULT_PING_INTERVAL_MS,
+            pong_timeout_ms: DEFAULT_PONG_TIMEOUT_MS,
+            overflow: Overflow::Resync,
+            overflow_grace_ms: DEFAULT_OVERFLOW_GRACE_MS,
+            game: GameDefaults::default(),
+        }
+    }
+}
+
+impl Default for GameDefaults {
+    fn default() -> Self {
+        let config = game::SnapConfig::default();
+        Self {
+            players: config.num_players,
+            best_of: config.best_of,
+            draw_cooldown_ms: millis(config.draw_cooldown),
+            response_window_ms: millis(config.response_window),
+            rules: config.rules,
+        }
+    }
+}
+
+fn parse_card_value(value: &str) -> Result<game::cards::Value, String> {
+    game::cards::Value::deserialize(value.into_deserializer())
+        .map_err(|e: serde::de::value::Error| e.to_string())
+}
+
+const fn millis(duration: Duration) -> u64 {
+    duration.as_millis() as u64
+}
+
+impl Cli {
+    /// Work out the server's settings, or explain what's wrong with them
+    pub fn server_config(&self) -> Result<ServerConfig, String> {
+        let mut config = match &self.config {
+            Some(path) => ServerConfig::from_file(path)?,
+            None => ServerConfig::default(),
+        };
+        self.apply(&mut config);
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Override the config with whichever settings were given
+    fn apply(&self, config: &mut ServerConfig) {
+        config.bind_address = self.bind_address.unwrap_or(config.bind_address);
+        config.port = self.port.unwrap_or(config.port);
+        config.max_games = self.max_games.unwrap_or(config.max_games);
+        if let Some(static_dir) = &self.static_dir {
+            config.static_dir = static_dir.clone();
+        }
+        if let Some(database) = &self.database {
+            config.database = database.clone();
+        }
+        if let Some(game_log_dir) = &self.game_log_dir {
+            config.game_log_dir = game_log_dir.clone();
+        }
+        config.reconnect_grace_secs = self
+            .reconnect_grace_secs
+            .unwrap_or(config.reconnect_grace_secs);
+        config.quickplay_timeout_secs = self
+            .quickplay_timeout_secs
+            .unwrap_or(config.quickplay_timeout_secs);
+        config.quickplay_bot_after_secs = self
+            .quickplay_bot_after_secs
+            .unwrap_or(config.quickplay_bot_after_secs);
+        config.shutdown_drain_secs = self
+            .shutdown_drain_secs
+            .unwrap_or(config.shutdown_drain_secs);
+        config.ping_interval_ms = self.ping_interval_ms.unwrap_or(config.ping_interval_ms);
+        config.pong_timeout_ms = self.pong_timeout_ms.unwrap_or(config.pong_timeout_ms);
+        config.overflow = self.overflow.unwrap_or(config.overflow);
+        config.overflow_grace_ms = self.overflow_grace_ms.unwrap_or(config.overflow_grace_ms);
+
+        let game = &mut config.game;
+        game.players = self.players.unwrap_or(game.players);
+        game.best_of = self.best_of.unwrap_or(game.best_of);
+        game.draw_cooldown_ms = self.draw_cooldown_ms.unwrap_or(game.draw_cooldown_ms);
+        game.response_window_ms = self.response_window_ms.unwrap_or(game.response_window_ms);
+        if let Some(rules) = &self.rules {
+            game.rules.pairs = false;
+            game.rules.sandwich = false;
+            game.rules.same_suit = false;
+            game.rules.sum_to_ten = false;
+            for rule in rules {
+                match rule {
+                    Rule::Pairs => game.rules.pairs = true,
+                    Rule::Sandwich => game.rules.sandwich = true,
+                    Rule::SameSuit => game.rules.same_suit = true,
+                    Rule::SumToTen => game.rules.sum_to_ten = true,
+                }
+            }
+        }
+        game.rules.named_card = self.named_card.or(game.rules.named_card);
+    }
+}
+
+impl ServerConfig {
+    fn from_file(path: &Path) -> Result<Self, String> {
+        let contents = std::fs::read_to_string(path)
+            .map_err(|e| format!("Could not read config file {}: {}", path.display(), e))?;
+        Self::from_toml(&contents)
+            .map_err(|e| format!("Could not parse config file {}: {}", path.display(), e))
+    }
+
+    fn from_toml(contents: &str) -> Result<Self, toml::de::Error> {
+        toml::from_str(contents)
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        if self.max_games == 0 {
+            return Err("max_games must be at least 1".to_owned());
+        }
+        // The frontend is built in with the `embed` feature
+        if !cfg!(feature = "embed") && !self.static_dir.is_dir() {
+            return Err(format!(
+                "Static asset directory {} does not exist",
+                self.static_dir.display()
+            ));
+        }
+        if self.ping_interval_ms == 0 {
+            return Err("ping_interval_ms must be more than 0".to_owned());
+        }
+        // Quiet clients only answer pings, so anything shorter would drop them
+        // between pings
+        if self.pong_timeout_ms <= self.ping_interval_ms {
+            return Err("pong_timeout_ms must be longer than ping_interval_ms".to_owned());
+        }
+        if self.game.response_window_ms == 0 {
+            return Err("response_window_ms must be more than 0".to_owned());
+        }
+        self.game
+            .snap_config()
+            .validate()
+            .map_err(|e| format!("Invalid game settings: {}", e))
+    }
+
+    pub fn reconnect_grace_period(&self) -> Duration {
+        Duration::from_secs(self.reconnect_grace_secs)
+    }
+
+    pub fn quickplay_timeout(&self) -> Duration {
+        Duration::from_secs(self.quickplay_timeout_secs)
+    }
+
+    pub fn shutdown_drain(&self) -> Duration {
+        Duration::from_secs(self.shutdown_drain_secs)
+    }
+
+    pub fn heartbeat(&self) -> websocket::Heartbeat {
+        websocket::Heartbeat {
+            ping_interval: Duration::from_millis(self.ping_interval_ms),
+            pong_timeout: Duration::from_millis(self.pong_timeout_ms),
+        }
+    }
+
+    pub fn overflow_policy(&self) -> websocket::OverflowPolicy {
+        match self.overflow {
+            Overflow::Grace => {
+                websocket::OverflowPolicy::Grace(Duration::from_millis(self.overflow_grace_ms))
+            }
+            Overflow::Disconnect => websocket::OverflowPolicy::Disconnect,
+            Overflow::Resync => websocket::OverflowPolicy::Resync,
+        }
+    }
+
+    pub fn quickplay_bot_after(&self) -> Option<Duration> {
+        (self.quickplay_bot_after_secs > 0)
+            .then(|| Duration::from_secs(self.quickplay_bot_after_secs))
+    }
+}
+
+impl GameDefaults {
+    pub fn snap_config(&self) -> game::SnapConfig {
+        game::SnapConfig {
+            num_players: self.players,
+            best_of: self.best_of,
+            draw_cooldown: Duration::from_millis(self.draw_cooldown_ms),
+            response_window: Duration::from_millis(self.response_window_ms),
+            rules: self.rules.clone(),
+            ..game::SnapConfig::default()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cli(args: &[&str]) -> Cli {
+        Cli::try_parse_from(std::iter::once("snap-backend").chain(args.iter().copied())).unwrap()
+    }
+
+    #[test]
+    fn flags_override_file() {
+        let mut config = ServerConfig::from_toml(
+            "
+            port = 8080
+            max_games = 10
+
+            [game]
+            players = 3
+
+            [game.rules]
+            sandwich = true
+            ",
+        )
+        .unwrap();
+        assert!(config.game.rules.pairs);
+        assert!(config.game.rules.sandwich);
+
+        cli(&[
+            "--port",
+            "9000",
+            "--rules",
+            "same-suit,sum-to-ten",
+            "--named-card",
+            "Queen",
+        ])
+        .apply(&mut config);
+        assert_eq!(config.port, 9000);
+        assert_eq!(config.max_games, 10);
+        let game = config.game.snap_config();
+        assert_eq!(game.num_players, 3);
+        assert!(!game.rules.pairs && !game.rules.sandwich);
+        assert!(game.rules.same_suit && game.rules.sum_to_ten);
+        assert_eq!(game.rules.named_card, Some(game::cards::Value::Queen));
+    }
+
+    #[test]
+    fn unknown_settings_rejected() {
+        assert!(ServerConfig::from_toml("prot = 8080").is_err());
+        assert!(Cli::try_parse_from(["snap-backend", "--port", "99999"]).is_err());
+        assert!(Cli::try_parse_from(["snap-backend", "--named-card", "Joker"]).is_err());
+    }
+
+    #[test]
+    fn invalid_game_settings_rejected() {
+        let mut config = ServerConfig {
+            static_dir: std::env::temp_dir(),
+            ..ServerConfig::default()
+        };
+        assert!(config.validate().is_ok());
+        config.game.players = 1;
+        assert!(config.validate().is_err());
+        config.game.players = 2;
+        config.game.best_of = 2;
+        assert!(config.validate().is_err());
+        config.game.best_of = 3;
+        config.pong_timeout_ms = config.ping_interval_ms;
+        assert!(config.validate().is_err());
+    }
+}
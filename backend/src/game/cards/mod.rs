@@ -1,8 +1,9 @@
 use itertools::iproduct;
+use rand::Rng;
 use rand::seq::SliceRandom;
 use serde::{Deserialize, Serialize};
 
-#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub enum Suit {
     Clubs,
     Hearts,
@@ -27,13 +28,38 @@ pub enum Value {
     Ace,
 }
 
-#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
+impl Value {
+    /// Number of pips on the card, counting aces as one. Face cards have none.
+    pub fn pips(&self) -> Option<u8> {
+        match self {
+            Value::Ace => Some(1),
+            Value::Two => Some(2),
+            Value::Three => Some(3),
+            Value::Four => Some(4),
+            Value::Five => Some(5),
+            Value::Six => Some(6),
+            Value::Seven => Some(7),
+            Value::Eight => Some(8),
+            Value::Nine => Some(9),
+            Value::Ten => Some(10),
+            Value::Jack | Value::Queen | Value::King => None,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub struct Card {
     pub suit: Suit,
     pub value: Value,
 }
 
 impl Card {
+    #[allow(dead_code)] // Handy when debugging
+    #[allow(
+        clippy::inherent_to_string,
+        clippy::wrong_self_convention,
+        clippy::needless_return
+    )]
     pub fn to_string(&self) -> String {
         let mut name = match self.value {
             Value::Two => "2",
@@ -67,19 +93,28 @@ impl CardPile {
     pub fn new() -> Self {
         CardPile(Vec::with_capacity(52))
     }
-    pub fn shuffle(&mut self) {
-        let mut rng = rand::rng();
-        self.0.shuffle(&mut rng);
+    pub fn shuffle(&mut self, rng: &mut impl Rng) {
+        self.0.shuffle(rng);
     }
 
     pub fn is_empty(&self) -> bool {
-        self.0.len() == 0
+        self.0.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    /// The cards in the pile, from the bottom up
+    pub fn cards(&self) -> &[Card] {
+        &self.0
     }
 
     pub fn last(&self) -> Option<&Card> {
         self.0.last()
     }
 
+    #[allow(clippy::needless_return)]
     pub fn penultimate(&self) -> Option<&Card> {
         if self.0.len() < 2 {
             return None;
@@ -87,6 +122,13 @@ impl CardPile {
         return Some(&self.0[self.0.len() - 2]);
     }
 
+    pub fn antepenultimate(&self) -> Option<&Card> {
+        if self.0.len() < 3 {
+            return None;
+        };
+        Some(&self.0[self.0.len() - 3])
+    }
+
     pub fn draw(&mut self) -> Option<Card> {
         self.0.pop()
     }
@@ -129,16 +171,33 @@ fn new_deck() -> CardPile {
     CardPile(cards)
 }
 
-/// Deal a shuffled deck into two piles
-pub fn deal_deck() -> (CardPile, CardPile) {
+impl From<Vec<Card>> for CardPile {
+    fn from(cards: Vec<Card>) -> Self {
+        CardPile(cards)
+    }
+}
+
+/// Deal a shuffled deck one card at a time into `num_hands` piles. If the deck
+/// doesn't divide evenly, the first piles get one card more.
+pub fn deal_deck(num_hands: usize, rng: &mut impl Rng) -> Vec<CardPile> {
     let mut deck = new_deck();
-    deck.shuffle();
+    deck.shuffle(rng);
 
-    let mut left = CardPile::new();
-    let mut right = CardPile::new();
+    let mut hands: Vec<CardPile> = (0..num_hands).map(|_| CardPile::new()).collect();
+    for (i, card) in deck.0.into_iter().enumerate() {
+        hands[i % num_hands].place(card);
+    }
+    hands
+}
 
-    left.0.extend_from_slice(&deck.0[0..26]);
-    right.0.extend_from_slice(&deck.0[26..52]);
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    return (left, right);
+    #[test]
+    fn deal_deck_uneven_hands() {
+        let hands = deal_deck(3, &mut rand::rng());
+        let sizes: Vec<usize> = hands.iter().map(|hand| hand.0.len()).collect();
+        assert_eq!(sizes, vec![18, 17, 17]);
+    }
 }
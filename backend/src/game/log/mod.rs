@@ -0,0 +1,189 @@
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+
+use super::{InputMessageType, OutputMessage, PlayerNumber, ResponseTimeMs, Snap, SnapConfig};
+use crate::manager::Game;
+use crate::message;
+
+/// Everything that happened in a game, in order. Feeding the events back into
+/// a game with the same config and seed reproduces it exactly.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct GameLog {
+    pub config: SnapConfig,
+    pub seed: u64,
+    pub entries: Vec<LogEntry>,
+    /// Times in the log are measured from here
+    #[serde(skip, default = "Instant::now")]
+    started: Instant,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct LogEntry {
+    pub event: LogEvent,
+    /// Messages the game sent in response to the event
+    pub outputs: Vec<OutputMessage>,
+}
+
+/// Something that advanced the game. Times are measured from the start of the
+/// game.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub enum LogEvent {
+    Input {
+        sender: PlayerNumber,
+        message: InputMessageType,
+        received_at: Duration,
+        round_trip: Option<Duration>,
+    },
+    Tick {
+        now: Duration,
+    },
+    Resume {
+        paused_for: Duration,
+    },
+    /// A player claimed a response time the server doesn't believe. Recorded
+    /// just before the response itself, and doesn't change the game.
+    SuspiciousResponse {
+        player: PlayerNumber,
+        claimed: ResponseTimeMs,
+        measured: Duration,
+        round_trip: Duration,
+        /// Whether this made the player's suspicious responses reach the limit
+        flagged: bool,
+    },
+}
+
+impl GameLog {
+    pub fn new(config: SnapConfig, seed: u64) -> Self {
+        Self {
+            config,
+            seed,
+            entries: vec![],
+            started: Instant::now(),
+        }
+    }
+
+    /// Time since the start of the game. Anything before the start is treated
+    /// as the start itself.
+    pub fn offset(&self, time: Instant) -> Duration {
+        time.saturating_duration_since(self.started)
+    }
+
+    pub fn record(&mut self, event: LogEvent, outputs: &[OutputMessage]) {
+        self.entries.push(LogEntry {
+            event,
+            outputs: outputs.to_vec(),
+        });
+    }
+}
+
+/// Where a replay stopped matching the original game
+#[derive(Debug)]
+pub struct ReplayError {
+    /// Index of the entry that didn't match
+    pub entry: usize,
+    pub expected: Vec<OutputMessage>,
+    pub actual: Vec<OutputMessage>,
+}
+
+/// Play the log's events through a fresh game, checking the game responds to
+/// each exactly as it did originally. Returns the replayed game.
+pub fn replay(log: &GameLog) -> Result<Snap, ReplayError> {
+    let mut game = Snap::new(SnapConfig {
+        seed: Some(log.seed),
+        ..log.config.clone()
+    });
+    let started = game.log.started;
+    for (i, entry) in log.entries.iter().enumerate() {
+        let actual = match entry.event {
+            LogEvent::Input {
+                sender,
+                message,
+                received_at,
+                round_trip,
+            } => game.player_action(message::InputMessage {
+                sender,
+                message,
+                received_at: started + received_at,
+                round_trip,
+            }),
+            LogEvent::Tick { now } => game.tick(started + now),
+            LogEvent::Resume { paused_for } => {
+                game.resume(paused_for);
+                vec![]
+            }
+            // The game records these again as it replays the responses
+            LogEvent::SuspiciousResponse { .. } => continue,
+        };
+        if actual != entry.outputs {
+            return Err(ReplayError {
+                entry: i,
+                expected: entry.outputs.clone(),
+                actual,
+            });
+        }
+    }
+    Ok(game)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{OutputMessageType, cards};
+    use super::*;
+
+    #[test]
+    fn replay_reproduces_game() {
+        let mut game = Snap::new(SnapConfig {
+            draw_cooldown: Duration::ZERO,
+            ..SnapConfig::default()
+        });
+        let start = Instant::now();
+        for i in 0..40 {
+            let now = start + Duration::from_millis(10 * i);
+            let sender = game.player_turn;
+            game.player_action(message::InputMessage {
+                sender,
+                message: InputMessageType::Draw(0),
+                received_at: now,
+                round_trip: None,
+            });
+            if game.response_deadline.is_some() {
+                game.tick(now + game.config.response_window);
+            }
+        }
+        game.resume(Duration::from_secs(1));
+
+        let log: GameLog =
+            serde_json::from_str(&serde_json::to_string(game.log()).unwrap()).unwrap();
+        let replayed = replay(&log).unwrap();
+        assert_eq!(replayed.log().entries.len(), game.log().entries.len());
+        assert_eq!(replayed.snapshot().hand_sizes, game.snapshot().hand_sizes);
+    }
+
+    #[test]
+    fn replay_detects_divergence() {
+        let mut game = Snap::new(SnapConfig::default());
+        game.player_action(message::InputMessage {
+            sender: 0,
+            message: InputMessageType::Draw(0),
+            received_at: Instant::now(),
+            round_trip: None,
+        });
+
+        let mut log = game.log().clone();
+        log.entries[0].outputs = vec![message::OutputMessage {
+            recipient: 0,
+            message: OutputMessageType::CardDrawn {
+                card: cards::Card {
+                    suit: cards::Suit::Clubs,
+                    value: cards::Value::Two,
+                },
+                from: 1,
+            },
+        }];
+        let Err(error) = replay(&log) else {
+            panic!("Replay should not match");
+        };
+        assert_eq!(error.entry, 0);
+    }
+}
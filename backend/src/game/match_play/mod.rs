@@ -0,0 +1,212 @@
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+
+use super::{InputMessage, InputMessageType, OutputMessage, OutputMessageType, Snap, SnapConfig};
+use crate::manager::Game;
+
+/// Rounds won by each player so far in a match
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct MatchScore {
+    pub round_wins: Vec<usize>,
+    pub best_of: usize,
+}
+
+/// A match of snap: A series of rounds, won by whoever wins most of them.
+/// Playing again after a round starts the next one, and playing again after
+/// the match is won starts a new match.
+pub struct SnapMatch {
+    round: Snap,
+    best_of: usize,
+    round_wins: Vec<usize>,
+}
+
+impl SnapMatch {
+    fn new(config: SnapConfig) -> Self {
+        Self {
+            best_of: config.best_of,
+            round_wins: vec![0; config.num_players],
+            round: Snap::new(config),
+        }
+    }
+
+    /// The round currently being played
+    pub fn round(&self) -> &Snap {
+        &self.round
+    }
+
+    /// The score, unless the match is a single round
+    pub fn score(&self) -> Option<MatchScore> {
+        (self.best_of > 1).then(|| MatchScore {
+            round_wins: self.round_wins.clone(),
+            best_of: self.best_of,
+        })
+    }
+
+    fn is_won(&self) -> bool {
+        self.round_wins.iter().any(|&wins| wins > self.best_of / 2)
+    }
+
+    /// Update the score if the round has just ended, and announce the score
+    /// whenever a round ends or a new one starts. There's nothing to announce
+    /// for single rounds.
+    fn keep_score(
+        &mut self,
+        round_was_over: bool,
+        mut messages: Vec<OutputMessage>,
+    ) -> Vec<OutputMessage> {
+        let round_is_over = self.round.has_ended();
+        if round_was_over == round_is_over {
+            return messages;
+        }
+        let round_winner = self.round.winner().filter(|_| round_is_over);
+        if let Some(winner) = round_winner {
+            self.round_wins[winner] += 1;
+        }
+        let Some(score) = self.score() else {
+            return messages;
+        };
+        messages.extend(
+            self.round
+                .to_all_players(OutputMessageType::MatchScore(score.clone())),
+        );
+        if let Some(winner) = round_winner
+            && self.is_won()
+        {
+            messages.extend(self.round.to_all_players(OutputMessageType::MatchWon {
+                player: winner,
+                score,
+            }));
+        }
+        messages
+    }
+}
+
+impl Game for SnapMatch {
+    type InputMessage = InputMessageType;
+    type OutputMessage = OutputMessageType;
+    type Config = SnapConfig;
+
+    fn num_players(config: &SnapConfig) -> usize {
+        config.num_players
+    }
+
+    fn new(config: SnapConfig) -> Self {
+        SnapMatch::new(config)
+    }
+
+    fn player_action(&mut self, message: InputMessage) -> Vec<OutputMessage> {
+        let round_was_over = self.round.has_ended();
+        if round_was_over && self.is_won() && message.message == InputMessageType::PlayAgain {
+            // Start a new match
+            self.round_wins.fill(0);
+        }
+        let messages = self.round.player_action(message);
+        self.keep_score(round_was_over, messages)
+    }
+
+    fn tick(&mut self, now: Instant) -> Vec<OutputMessage> {
+        let round_was_over = self.round.has_ended();
+        let messages = self.round.tick(now);
+        self.keep_score(round_was_over, messages)
+    }
+
+    fn next_deadline(&self) -> Option<Instant> {
+        self.round.next_deadline()
+    }
+
+    fn resume(&mut self, paused_for: Duration) {
+        self.round.resume(paused_for);
+    }
+
+    fn is_public(message: &OutputMessageType) -> bool {
+        Snap::is_public(message)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::cards;
+    use super::*;
+    use crate::message;
+
+    fn action(sender: usize, message: InputMessageType) -> InputMessage {
+        message::InputMessage {
+            sender,
+            message,
+            received_at: Instant::now(),
+            round_trip: None,
+        }
+    }
+
+    /// Have player 0 win the round by drawing their last card
+    fn win_round(game: &mut SnapMatch) -> Vec<OutputMessage> {
+        let mut hands = vec![vec![], vec![]];
+        hands[0].push(cards::Card {
+            suit: cards::Suit::Clubs,
+            value: cards::Value::Two,
+        });
+        hands[1].push(cards::Card {
+            suit: cards::Suit::Hearts,
+            value: cards::Value::Three,
+        });
+        for (player, hand) in game.round.players.iter_mut().zip(hands) {
+            player.hand = cards::CardPile::from(hand);
+        }
+        game.round.player_turn = 0;
+        game.player_action(action(0, InputMessageType::Draw(0)))
+    }
+
+    fn announces(messages: &[OutputMessage], f: impl Fn(&OutputMessageType) -> bool) -> bool {
+        messages.iter().any(|m| m.recipient == 0 && f(&m.message))
+    }
+
+    #[test]
+    fn match_won_after_majority_of_rounds() {
+        let mut game = SnapMatch::new(SnapConfig {
+            best_of: 3,
+            draw_cooldown: Duration::ZERO,
+            ..SnapConfig::default()
+        });
+
+        let messages = win_round(&mut game);
+        assert_eq!(game.round_wins, vec![1, 0]);
+        assert!(!announces(&messages, |m| matches!(
+            m,
+            OutputMessageType::MatchWon { .. }
+        )));
+
+        let messages = game.player_action(action(1, InputMessageType::PlayAgain));
+        assert!(announces(&messages, |m| *m == OutputMessageType::GameRestarted));
+        // Players take turns to go first
+        assert_eq!(game.round.player_turn, 1);
+
+        let messages = win_round(&mut game);
+        assert!(announces(&messages, |m| matches!(
+            m,
+            OutputMessageType::MatchWon { player: 0, .. }
+        )));
+
+        game.player_action(action(1, InputMessageType::PlayAgain));
+        assert_eq!(game.round_wins, vec![0, 0]);
+    }
+
+    #[test]
+    fn single_round_keeps_no_score() {
+        let mut game = SnapMatch::new(SnapConfig {
+            best_of: 1,
+            draw_cooldown: Duration::ZERO,
+            ..SnapConfig::default()
+        });
+        let messages = win_round(&mut game);
+        assert!(announces(&messages, |m| matches!(
+            m,
+            OutputMessageType::PlayerWins(0)
+        )));
+        assert!(!announces(&messages, |m| matches!(
+            m,
+            OutputMessageType::MatchScore(_) | OutputMessageType::MatchWon { .. }
+        )));
+        assert_eq!(game.score(), None);
+    }
+}
@@ -1,8 +1,18 @@
+use std::time::{Duration, Instant};
+
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
 use serde::{Deserialize, Serialize};
 
-mod cards;
+pub mod cards;
+pub mod log;
+mod match_play;
+mod rules;
 use crate::manager;
 use crate::message;
+use log::{GameLog, LogEvent};
+pub use match_play::{MatchScore, SnapMatch};
+pub use rules::SnapRules;
 
 /// Milliseconds taken for user to respond, measured by their browser
 /// 2^32 ms ~ 50 days
@@ -11,11 +21,35 @@ type ResponseTimeMs = u32;
 /// Player's position at the table
 pub type PlayerNumber = usize;
 
-/// In case I want to generalize later
-const NUM_PLAYERS: usize = 2;
+/// Number of players at a table, unless configured otherwise
+const DEFAULT_NUM_PLAYERS: usize = 2;
+pub const MIN_PLAYERS: usize = 2;
+pub const MAX_PLAYERS: usize = 8;
+
+/// Minimum time between two draws, unless configured otherwise
+const DEFAULT_DRAW_COOLDOWN: Duration = Duration::from_millis(300);
+
+/// How long players have to respond to a possible snap, unless configured
+/// otherwise
+const DEFAULT_RESPONSE_WINDOW: Duration = Duration::from_secs(3);
+
+/// Longest series of rounds a match can be played over
+pub const MAX_BEST_OF: usize = 7;
+
+/// Nobody reacts faster than this; quicker response times are not plausible
+const MIN_REACTION_TIME: Duration = Duration::from_millis(100);
+
+/// Slack given to response times for jitter in the connection
+const RESPONSE_TIME_TOLERANCE: Duration = Duration::from_millis(100);
+
+/// Round-trip time we allow for when a connection's hasn't been measured yet
+const UNMEASURED_ROUND_TRIP: Duration = Duration::from_millis(500);
+
+/// Flag a player once this many of their response times have been implausible
+const SUSPICIOUS_RESPONSE_LIMIT: usize = 3;
 
 /// The allowed in-game messages from the client
-#[derive(Copy, Clone, Debug, Deserialize, Serialize)]
+#[derive(Copy, Clone, Debug, PartialEq, Deserialize, Serialize)]
 pub enum InputMessageType {
     /// User drew a card
     Draw(ResponseTimeMs),
@@ -28,6 +62,21 @@ pub enum InputMessageType {
 }
 
 impl InputMessageType {
+    fn response_time(&self) -> Option<ResponseTimeMs> {
+        match self {
+            Self::Draw(time) | Self::Snap(time) => Some(*time),
+            Self::NoResponse | Self::PlayAgain => None,
+        }
+    }
+
+    fn with_response_time(self, time: ResponseTimeMs) -> Self {
+        match self {
+            Self::Draw(_) => Self::Draw(time),
+            Self::Snap(_) => Self::Snap(time),
+            Self::NoResponse | Self::PlayAgain => self,
+        }
+    }
+
     pub fn was_faster_than(&self, other: &Self) -> bool {
         match self {
             Self::NoResponse | Self::PlayAgain => false,
@@ -42,7 +91,7 @@ impl InputMessageType {
 
 type InputMessage = message::InputMessage<PlayerNumber, InputMessageType>;
 
-#[derive(Copy, Clone, Debug, Deserialize, Serialize)]
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
 pub enum OutputMessageType {
     CardDrawn {
         card: cards::Card,
@@ -54,47 +103,219 @@ pub enum OutputMessageType {
         is_mistake: bool,
     },
     PlayerTakesCenter(PlayerNumber),
+    /// Player takes some of the center pile; used when it's split between
+    /// several players
+    PlayerTakesCards {
+        player: PlayerNumber,
+        count: usize,
+    },
     PlayerWins(PlayerNumber),
-    InvalidDraw,
+    /// Draw came too soon after the previous one; try again after `wait_ms`
+    DrawTooSoon {
+        wait_ms: u32,
+    },
     SomethingWentWrong,
     GameRestarted,
+    /// Everything on the table, for players that need to catch up
+    GameState(GameState),
+    /// Rounds won so far in the match; sent after each round of matches
+    /// longer than one round
+    MatchScore(MatchScore),
+    /// Player has won the majority of the match's rounds
+    MatchWon {
+        player: PlayerNumber,
+        score: MatchScore,
+    },
+    /// How each player did over the round; sent along with `PlayerWins`
+    GameStats(Vec<PlayerStats>),
+    /// The player's message was turned down, and did nothing
+    Rejected(message::ProtocolError),
+}
+
+/// Everything players can see on the table
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct GameState {
+    pub hand_sizes: Vec<usize>,
+    /// From the bottom of the pile up
+    pub center_pile: Vec<cards::Card>,
+    pub player_turn: PlayerNumber,
+    /// Each player's response to the current possible snap, if they've given one
+    pub pending_responses: Vec<Option<InputMessageType>>,
+    /// A snap is possible, so the game is waiting for everyone to respond
+    pub awaiting_responses: bool,
+    /// Set once the game is over
+    pub winner: Option<PlayerNumber>,
 }
 
-type OutputMessage = message::OutputMessage<PlayerNumber, OutputMessageType>;
+pub type OutputMessage = message::OutputMessage<PlayerNumber, OutputMessageType>;
+
+/// How a finished round went
+#[derive(Clone, Debug, PartialEq)]
+pub struct RoundStats {
+    pub winner: PlayerNumber,
+    /// From the first draw to the end of the round
+    pub duration: Duration,
+    /// Snap races won
+    pub snaps: usize,
+    /// Snaps made when the cards didn't match
+    pub mistakes: usize,
+}
+
+/// How one player did over a round
+#[derive(Clone, Debug, Default, PartialEq, Deserialize, Serialize)]
+pub struct PlayerStats {
+    pub snaps_won: usize,
+    /// Snaps made when the cards didn't match
+    pub false_snaps: usize,
+    pub draws: usize,
+    /// Mean response time of the player's snaps, if they made any in time
+    pub average_reaction_ms: Option<ResponseTimeMs>,
+    /// Most cards the player had to pick up at once
+    pub largest_pile_taken: usize,
+}
 
 struct Player {
     hand: cards::CardPile,
     pending_message: Option<InputMessageType>,
+    /// Number of response times this player has claimed that we didn't believe
+    suspicious_responses: usize,
+    stats: PlayerStats,
+    /// Response times of the player's snaps this round
+    reaction_times: Vec<ResponseTimeMs>,
 }
 
-pub struct Snap {
-    players: [Player; NUM_PLAYERS],
-    player_turn: PlayerNumber,
-    center_pile: cards::CardPile,
+impl Player {
+    fn record_reaction(&mut self, time: ResponseTimeMs) {
+        self.reaction_times.push(time);
+        let total: u64 = self.reaction_times.iter().map(|&t| u64::from(t)).sum();
+        let average = total / self.reaction_times.len() as u64;
+        self.stats.average_reaction_ms = Some(average.try_into().unwrap_or(ResponseTimeMs::MAX));
+    }
+
+    fn record_pile_taken(&mut self, count: usize) {
+        self.stats.largest_pile_taken = self.stats.largest_pile_taken.max(count);
+    }
+}
+
+/// Settings for a game of snap
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct SnapConfig {
+    pub num_players: usize,
+    /// Minimum time between draws, measured by the server
+    pub draw_cooldown: Duration,
+    /// How long players have to respond once a snap is possible, before the
+    /// server responds for them
+    pub response_window: Duration,
+    pub rules: SnapRules,
+    /// Seed for the game's shuffles. Picked at random if not given; reusing
+    /// the seed of an earlier game deals exactly the same cards.
+    pub seed: Option<u64>,
+    /// Number of rounds in a match; whoever wins most of them wins the match
+    pub best_of: usize,
 }
 
-impl Default for Snap {
+impl Default for SnapConfig {
     fn default() -> Self {
-        let (hand1, hand2) = cards::deal_deck();
-        let players = [
-            Player {
-                hand: hand1,
-                pending_message: None,
-            },
-            Player {
-                hand: hand2,
-                pending_message: None,
-            },
-        ];
         Self {
-            players,
-            player_turn: 0,
-            center_pile: cards::CardPile::new(),
+            num_players: DEFAULT_NUM_PLAYERS,
+            draw_cooldown: DEFAULT_DRAW_COOLDOWN,
+            response_window: DEFAULT_RESPONSE_WINDOW,
+            rules: SnapRules::default(),
+            seed: None,
+            best_of: 1,
+        }
+    }
+}
+
+impl SnapConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if !(MIN_PLAYERS..=MAX_PLAYERS).contains(&self.num_players) {
+            return Err(format!(
+                "Number of players must be between {} and {}",
+                MIN_PLAYERS, MAX_PLAYERS
+            ));
         }
+        if self.best_of.is_multiple_of(2) || !(1..=MAX_BEST_OF).contains(&self.best_of) {
+            return Err(format!(
+                "Matches must be best of an odd number of rounds, up to {}",
+                MAX_BEST_OF
+            ));
+        }
+        self.rules.validate()
     }
 }
 
+pub struct Snap {
+    config: SnapConfig,
+    players: Vec<Player>,
+    player_turn: PlayerNumber,
+    center_pile: cards::CardPile,
+    /// When the server accepted the most recent draw
+    last_draw: Option<Instant>,
+    /// When the current snap response window closes, if one is open
+    response_deadline: Option<Instant>,
+    /// Nobody snapped the matching cards on top of the center pile in time
+    snap_missed: bool,
+    /// Rounds dealt so far; each round a different player goes first
+    rounds_dealt: usize,
+    /// When the first card of the round was drawn
+    round_started: Option<Instant>,
+    /// When the round was won
+    round_ended: Option<Instant>,
+    /// Seed `rng` was created from, so the game can be reproduced
+    seed: u64,
+    /// Source of every shuffle in the game
+    rng: StdRng,
+    log: GameLog,
+}
+
 impl Snap {
+    fn new(config: SnapConfig) -> Self {
+        let seed = config.seed.unwrap_or_else(|| rand::rng().random());
+        println!("Starting game with seed {}", seed);
+        let mut game = Self {
+            log: GameLog::new(config.clone(), seed),
+            config,
+            players: vec![],
+            player_turn: 0,
+            center_pile: cards::CardPile::new(),
+            last_draw: None,
+            response_deadline: None,
+            snap_missed: false,
+            rounds_dealt: 0,
+            round_started: None,
+            round_ended: None,
+            seed,
+            rng: StdRng::seed_from_u64(seed),
+        };
+        game.deal();
+        game
+    }
+
+    /// Start a fresh round: Deal the cards, clear the table, and pass the first
+    /// turn on to the next player
+    fn deal(&mut self) {
+        self.players = cards::deal_deck(self.config.num_players, &mut self.rng)
+            .into_iter()
+            .map(|hand| Player {
+                hand,
+                pending_message: None,
+                suspicious_responses: 0,
+                stats: PlayerStats::default(),
+                reaction_times: vec![],
+            })
+            .collect();
+        self.player_turn = self.rounds_dealt % self.config.num_players;
+        self.rounds_dealt += 1;
+        self.center_pile = cards::CardPile::new();
+        self.last_draw = None;
+        self.response_deadline = None;
+        self.snap_missed = false;
+        self.round_started = None;
+        self.round_ended = None;
+    }
+
     // Game entered an unexpected state, abort, log, and notify players
     fn abort(&mut self, _reason: &str) -> Vec<OutputMessage> {
         println!("Something went wrong");
@@ -102,40 +323,163 @@ impl Snap {
     }
 
     fn clear_pending_messages(&mut self) {
-        for player in (0..NUM_PLAYERS) {
-            self.players[player].pending_message = None;
+        for player in self.players.iter_mut() {
+            player.pending_message = None;
         }
     }
 
-    fn snap_possible(&self) -> bool {
-        match (self.center_pile.last(), self.center_pile.penultimate()) {
-            (Some(last), Some(penultimate)) => last.value == penultimate.value,
-            _ => false,
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    pub fn log(&self) -> &GameLog {
+        &self.log
+    }
+
+    pub fn rules(&self) -> &SnapRules {
+        &self.config.rules
+    }
+
+    /// How the round went, once it's over
+    pub fn round_stats(&self) -> Option<RoundStats> {
+        let winner = self.winner().filter(|_| self.has_ended())?;
+        let duration = match (self.round_started, self.round_ended) {
+            (Some(started), Some(ended)) => ended.saturating_duration_since(started),
+            _ => Duration::ZERO,
+        };
+        let players = self.player_stats();
+        Some(RoundStats {
+            winner,
+            duration,
+            snaps: players.iter().map(|p| p.snaps_won).sum(),
+            mistakes: players.iter().map(|p| p.false_snaps).sum(),
+        })
+    }
+
+    fn player_stats(&self) -> Vec<PlayerStats> {
+        self.players.iter().map(|p| p.stats.clone()).collect()
+    }
+
+    /// Note the time if `now` is when the round was won
+    fn check_round_ended(&mut self, now: Instant) {
+        if self.has_ended() && self.round_ended.is_none() {
+            self.round_ended = Some(now);
         }
     }
 
+    fn cards_match(&self) -> bool {
+        self.config.rules.snap_possible(&self.center_pile)
+    }
+
+    fn snap_possible(&self) -> bool {
+        self.cards_match() && !self.snap_missed
+    }
+
     /// Game ends when a player gets rid of all their cards
     fn has_ended(&self) -> bool {
-        (!self.snap_possible()) && self.players.iter().any(|p| p.hand.is_empty())
+        (!self.snap_possible()) && self.winner().is_some()
+    }
+
+    /// The first player to have gotten rid of their cards, if any
+    fn winner(&self) -> Option<PlayerNumber> {
+        self.players.iter().position(|p| p.hand.is_empty())
+    }
+
+    /// The next player after the current one that still has cards to draw
+    fn next_player_turn(&self) -> PlayerNumber {
+        let num_players = self.players.len();
+        (1..=num_players)
+            .map(|offset| (self.player_turn + offset) % num_players)
+            .find(|&player| !self.players[player].hand.is_empty())
+            .unwrap_or(self.player_turn)
+    }
+
+    /// Tell everyone who won, and how each of them played
+    fn declare_winner(&self, winner: PlayerNumber) -> Vec<OutputMessage> {
+        let mut messages = self.to_all_players(OutputMessageType::PlayerWins(winner));
+        messages.extend(self.to_all_players(OutputMessageType::GameStats(self.player_stats())));
+        messages
     }
 
     fn to_all_players(&self, message: OutputMessageType) -> Vec<OutputMessage> {
-        (0..NUM_PLAYERS)
+        (0..self.players.len())
             .map(|player| message::OutputMessage {
                 recipient: player,
-                message,
+                message: message.clone(),
             })
             .collect()
     }
 
+    /// Everything on the table, for players that have missed some messages
+    pub fn snapshot(&self) -> GameState {
+        GameState {
+            hand_sizes: self.players.iter().map(|p| p.hand.len()).collect(),
+            center_pile: self.center_pile.cards().to_vec(),
+            player_turn: self.player_turn,
+            pending_responses: self.players.iter().map(|p| p.pending_message).collect(),
+            awaiting_responses: self.snap_possible(),
+            winner: self.winner().filter(|_| self.has_ended()),
+        }
+    }
+
+    /// How much longer a draw received at `now` would need to wait, if at all
+    fn draw_cooldown_remaining(&self, now: Instant) -> Option<Duration> {
+        let earliest_draw = self.last_draw? + self.config.draw_cooldown;
+        match earliest_draw.checked_duration_since(now) {
+            Some(remaining) if !remaining.is_zero() => Some(remaining),
+            _ => None,
+        }
+    }
+
+    /// Check the response time a player's browser reported against what the
+    /// server measured, and clamp it into the plausible range.
+    fn checked_response(&mut self, message: &InputMessage) -> InputMessageType {
+        let (Some(claimed), Some(card_drawn_at)) =
+            (message.message.response_time(), self.last_draw)
+        else {
+            return message.message;
+        };
+        let server_elapsed = message.received_at.saturating_duration_since(card_drawn_at);
+        let round_trip = message.round_trip.unwrap_or(UNMEASURED_ROUND_TRIP);
+        let (time, plausible) = plausible_response_time(claimed, server_elapsed, round_trip);
+        if !plausible {
+            let player = &mut self.players[message.sender];
+            player.suspicious_responses += 1;
+            println!(
+                "Player {} claimed a {}ms response but the server measured {}ms with a {}ms round trip",
+                message.sender,
+                claimed,
+                server_elapsed.as_millis(),
+                round_trip.as_millis(),
+            );
+            let flagged = player.suspicious_responses == SUSPICIOUS_RESPONSE_LIMIT;
+            if flagged {
+                println!(
+                    "Player {} flagged for repeatedly implausible response times",
+                    message.sender
+                );
+            }
+            let event = LogEvent::SuspiciousResponse {
+                player: message.sender,
+                claimed,
+                measured: server_elapsed,
+                round_trip,
+                flagged,
+            };
+            self.log.record(event, &[]);
+        }
+        message.message.with_response_time(time)
+    }
+
     /// Draw a card, notify players, and bump the turn counter.
     /// Also declare a winner if this draw ends the game.
-    fn draw_card(&mut self) -> Vec<OutputMessage> {
-        let player_hand = &mut self.players[self.player_turn].hand;
-        let card = match player_hand.draw() {
+    fn draw_card(&mut self, now: Instant) -> Vec<OutputMessage> {
+        let player = &mut self.players[self.player_turn];
+        let card = match player.hand.draw() {
             None => return self.abort("Draw from empty hand"),
             Some(card) => card,
         };
+        player.stats.draws += 1;
 
         // Alert each player a card has been drawn
         let mut messages: Vec<OutputMessage> = self.to_all_players(OutputMessageType::CardDrawn {
@@ -143,54 +487,140 @@ impl Snap {
             from: self.player_turn,
         });
 
-        // Add card to center pile
+        // Add card to center pile, and give everyone a chance to snap it
         self.center_pile.place(card);
+        self.last_draw = Some(now);
+        self.round_started.get_or_insert(now);
+        self.snap_missed = false;
+        if self.snap_possible() {
+            self.response_deadline = Some(now + self.config.response_window);
+        }
 
-        // If the game has ended, declare the current player the winner
-        if self.has_ended() {
-            messages.extend(self.to_all_players(OutputMessageType::PlayerWins(self.player_turn)));
-        } else {
-            self.player_turn = (self.player_turn + 1) % NUM_PLAYERS;
+        // If the game has ended, declare the winner
+        match self.winner() {
+            Some(winner) if self.has_ended() => {
+                messages.extend(self.declare_winner(winner));
+            }
+            _ => self.player_turn = self.next_player_turn(),
         }
-        return messages;
+        messages
     }
 
     fn player_takes_center(&mut self, player: PlayerNumber) -> Vec<OutputMessage> {
+        self.snap_missed = false;
+        self.players[player].record_pile_taken(self.center_pile.len());
         self.players[player].hand.absorb(&mut self.center_pile);
-        self.players[player].hand.shuffle();
+        self.players[player].hand.shuffle(&mut self.rng);
         self.player_turn = player;
         self.to_all_players(OutputMessageType::PlayerTakesCenter(player))
     }
-}
 
-impl manager::Game for Snap {
-    type InputMessage = InputMessageType;
-    type OutputMessage = OutputMessageType;
-    const NUM_PLAYERS: usize = 2;
+    /// Share the center pile out between the players that lost a snap race,
+    /// one card at a time. Losers without any cards are dealt to first, so
+    /// nobody wins the game by losing a snap. The first loser starts the next
+    /// round.
+    fn losers_take_center(&mut self, losers: &[PlayerNumber]) -> Vec<OutputMessage> {
+        let [first_loser, ..] = losers else {
+            return self.abort("Nobody lost the snap race");
+        };
+        if losers.len() == 1 {
+            return self.player_takes_center(*first_loser);
+        }
 
-    /// Advance the game and return any messages to be passed to users
-    fn player_action(&mut self, message: InputMessage) -> Vec<OutputMessage> {
+        self.snap_missed = false;
+        let mut counts = vec![0; losers.len()];
+        let mut dealing_order: Vec<usize> = (0..losers.len()).collect();
+        dealing_order.sort_by_key(|&i| !self.players[losers[i]].hand.is_empty());
+        for &i in dealing_order.iter().cycle() {
+            let Some(card) = self.center_pile.draw() else {
+                break;
+            };
+            self.players[losers[i]].hand.place(card);
+            counts[i] += 1;
+        }
+        self.player_turn = *first_loser;
+
+        let mut messages = vec![];
+        for (&player, count) in losers.iter().zip(counts) {
+            self.players[player].record_pile_taken(count);
+            self.players[player].hand.shuffle(&mut self.rng);
+            messages
+                .extend(self.to_all_players(OutputMessageType::PlayerTakesCards { player, count }));
+        }
+        messages
+    }
+
+    /// Everyone has replied to a possible snap: Decide how to proceed
+    fn resolve_responses(&mut self, now: Instant) -> Vec<OutputMessage> {
+        self.response_deadline = None;
+        let mut all_responses: Vec<InputMessageType> = self
+            .players
+            .iter()
+            .map(|p| p.pending_message.unwrap_or(InputMessageType::NoResponse))
+            .collect();
+        let (fastest_player, fastest_response) = match get_fastest_response(&mut all_responses) {
+            None => return self.abort("Could not determine winning response"),
+            Some((player, response)) => (player, response),
+        };
+
+        self.clear_pending_messages();
+        match fastest_response {
+            InputMessageType::NoResponse | InputMessageType::PlayAgain => {
+                // Nobody snapped in time; carry on as if the cards didn't match
+                self.snap_missed = true;
+                match self.winner() {
+                    Some(winner) => self.declare_winner(winner),
+                    None => vec![],
+                }
+            }
+            InputMessageType::Draw(_) => self.draw_card(now),
+            InputMessageType::Snap(_) => {
+                self.players[fastest_player].stats.snaps_won += 1;
+                // Everyone else lost the race, starting from the player after
+                // the winner
+                let num_players = self.players.len();
+                let losers: Vec<PlayerNumber> = (1..num_players)
+                    .map(|offset| (fastest_player + offset) % num_players)
+                    .collect();
+                let mut server_msgs = self.losers_take_center(&losers);
+                // Snapping with their last card out wins the game
+                let winner = Some(fastest_player)
+                    .filter(|&player| self.players[player].hand.is_empty())
+                    .or_else(|| self.winner());
+                if let Some(winner) = winner {
+                    server_msgs.extend(self.declare_winner(winner));
+                }
+                server_msgs
+            }
+        }
+    }
+
+    /// Work out how the game responds to a player's message
+    fn handle_action(&mut self, message: InputMessage) -> Vec<OutputMessage> {
         if self.has_ended() {
             return match message.message {
                 InputMessageType::PlayAgain => {
-                    *self = Snap::default();
+                    self.deal();
                     self.to_all_players(OutputMessageType::GameRestarted)
                 }
-                _ => log_invalid(message, "Game ended"),
+                _ => reject(message, message::ProtocolError::GameEnded),
             };
         }
 
-        // Player can only send "Draw" if it's their turn
-        match message.message {
-            InputMessageType::Draw(_) => {
-                if message.sender != self.player_turn {
-                    return vec![message::OutputMessage {
-                        recipient: message.sender,
-                        message: OutputMessageType::InvalidDraw,
-                    }]
-                }
+        // Player can only send "Draw" if it's their turn, and not too soon
+        // after the previous draw
+        if let InputMessageType::Draw(_) = message.message {
+            if message.sender != self.player_turn {
+                return reject(message, message::ProtocolError::NotYourTurn);
+            }
+            if let Some(remaining) = self.draw_cooldown_remaining(message.received_at) {
+                return vec![message::OutputMessage {
+                    recipient: message.sender,
+                    message: OutputMessageType::DrawTooSoon {
+                        wait_ms: remaining.as_millis().try_into().unwrap_or(u32::MAX),
+                    },
+                }];
             }
-            _ => {}
         }
 
         if !self.snap_possible() {
@@ -199,7 +629,7 @@ impl manager::Game for Snap {
             // the current player.
             return match message.message {
                 // We've already checked
-                InputMessageType::Draw(_) => self.draw_card(),
+                InputMessageType::Draw(_) => self.draw_card(message.received_at),
                 InputMessageType::Snap(_) => {
                     if self.center_pile.is_empty() {
                         // Player has made an incorrect snap, but there are no
@@ -207,6 +637,7 @@ impl manager::Game for Snap {
                         return vec![];
                     }
                     // Player has made an incorrect snap; they take the center
+                    self.players[message.sender].stats.false_snaps += 1;
                     let mut messages =
                         self.to_all_players(OutputMessageType::OtherPlayerResponded {
                             player: message.sender,
@@ -216,10 +647,8 @@ impl manager::Game for Snap {
                     messages.extend(self.player_takes_center(message.sender));
                     messages
                 }
-                _ => log_invalid(
-                    message,
-                    "Only valid message is \"draw\" from current player",
-                ),
+                // Only the current player's draw is valid
+                _ => reject(message, message::ProtocolError::NotYourTurn),
             };
         }
 
@@ -227,66 +656,161 @@ impl manager::Game for Snap {
         // continuing.
         //
         // First, store player's message and notify all other players.
-        if !self.players[message.sender].pending_message.is_none() {
-            return vec![];
+        if self.players[message.sender].pending_message.is_some() {
+            return reject(message, message::ProtocolError::DuplicateResponse);
+        }
+        let response = self.checked_response(&message);
+        let player = &mut self.players[message.sender];
+        player.pending_message = Some(response);
+        if let InputMessageType::Snap(time) = response {
+            player.record_reaction(time);
         }
-        self.players[message.sender].pending_message = Some(message.message);
         let mut server_msgs: Vec<OutputMessage> =
             self.to_all_players(OutputMessageType::OtherPlayerResponded {
                 player: message.sender,
-                msg: message.message,
+                msg: response,
                 is_mistake: false,
             });
 
         // If any player is still to respond, we continue waiting.
-        let maybe_all_responses: Option<Vec<_>> =
-            self.players.iter().map(|p| p.pending_message).collect();
-        match maybe_all_responses {
-            // Still waiting for someone to reply
-            None => return server_msgs,
-
-            // Everyone has replied: Decide how to proceed
-            Some(mut all_responses) => {
-                let (fastest_player, fastest_response) =
-                    match get_fastest_response(&mut all_responses) {
-                        None => return self.abort("Could not determine winning response"),
-                        Some((player, response)) => (player, response),
-                    };
-
-                self.clear_pending_messages();
-                match fastest_response {
-                    InputMessageType::NoResponse | InputMessageType::PlayAgain => {
-                        return self.abort("Unexpected fastest response type");
-                    }
-                    InputMessageType::Draw(_) => {
-                        server_msgs.extend(self.draw_card());
-                        server_msgs
-                    }
-                    InputMessageType::Snap(_) => {
-                        let loser = (fastest_player + 1) % 2;
-                        server_msgs.extend(self.player_takes_center(loser));
-                        if self.has_ended() {
-                            server_msgs.extend(self.to_all_players(OutputMessageType::PlayerWins(fastest_player)));
-                        }
-                        server_msgs
-                    }
-                }
-            }
+        if self.players.iter().all(|p| p.pending_message.is_some()) {
+            server_msgs.extend(self.resolve_responses(message.received_at));
+        }
+        server_msgs
+    }
+
+    /// Fill in a "no response" for anyone who hasn't replied once the
+    /// response window has closed
+    fn handle_tick(&mut self, now: Instant) -> Vec<OutputMessage> {
+        match self.response_deadline {
+            Some(deadline) if deadline <= now => {}
+            _ => return vec![],
+        };
+        let silent_players: Vec<PlayerNumber> = (0..self.players.len())
+            .filter(|&player| self.players[player].pending_message.is_none())
+            .collect();
+        let mut server_msgs = vec![];
+        for player in silent_players {
+            self.players[player].pending_message = Some(InputMessageType::NoResponse);
+            server_msgs.extend(
+                self.to_all_players(OutputMessageType::OtherPlayerResponded {
+                    player,
+                    msg: InputMessageType::NoResponse,
+                    is_mistake: false,
+                }),
+            );
+        }
+        server_msgs.extend(self.resolve_responses(now));
+        server_msgs
+    }
+}
+
+impl manager::Game for Snap {
+    type InputMessage = InputMessageType;
+    type OutputMessage = OutputMessageType;
+    type Config = SnapConfig;
+
+    fn num_players(config: &SnapConfig) -> usize {
+        config.num_players
+    }
+
+    fn new(config: SnapConfig) -> Self {
+        Snap::new(config)
+    }
+
+    /// Advance the game and return any messages to be passed to users
+    fn player_action(&mut self, message: InputMessage) -> Vec<OutputMessage> {
+        let event = LogEvent::Input {
+            sender: message.sender,
+            message: message.message,
+            received_at: self.log.offset(message.received_at),
+            round_trip: message.round_trip,
+        };
+        let received_at = message.received_at;
+        let outputs = self.handle_action(message);
+        self.check_round_ended(received_at);
+        self.log.record(event, &outputs);
+        outputs
+    }
+
+    fn tick(&mut self, now: Instant) -> Vec<OutputMessage> {
+        let event = LogEvent::Tick {
+            now: self.log.offset(now),
+        };
+        let outputs = self.handle_tick(now);
+        self.check_round_ended(now);
+        self.log.record(event, &outputs);
+        outputs
+    }
+
+    fn next_deadline(&self) -> Option<Instant> {
+        self.response_deadline
+    }
+
+    /// Time stood still while the game was paused
+    fn resume(&mut self, paused_for: Duration) {
+        if let Some(last_draw) = self.last_draw.as_mut() {
+            *last_draw += paused_for;
+        }
+        if let Some(deadline) = self.response_deadline.as_mut() {
+            *deadline += paused_for;
         }
+        self.log.record(LogEvent::Resume { paused_for }, &[]);
+    }
+
+    /// Everything sent to the whole table is public
+    fn is_public(message: &OutputMessageType) -> bool {
+        !matches!(
+            message,
+            OutputMessageType::DrawTooSoon { .. }
+                | OutputMessageType::GameState(_)
+                | OutputMessageType::Rejected(_)
+        )
     }
 }
 
-/// This message is not valid for this game state; log and return no messages to clients.
-fn log_invalid(message: InputMessage, reason: &str) -> Vec<OutputMessage> {
+/// This message is not valid for this game state; log it and tell the sender.
+fn reject(message: InputMessage, error: message::ProtocolError) -> Vec<OutputMessage> {
     println!(
         "Unexpected message \"{:?}\" from player {}; {}",
-        message.message, message.sender, reason
+        message.message, message.sender, error
     );
-    return vec![];
+    vec![message::OutputMessage {
+        recipient: message.sender,
+        message: OutputMessageType::Rejected(error),
+    }]
+}
+
+/// The server saw `server_elapsed` pass between a card being drawn and a
+/// response arriving, which includes one round trip of the connection. The
+/// player's real reaction time can't be more than that, and shouldn't be much
+/// less than that minus the round trip. Returns the claimed time clamped to
+/// that range, and whether the claim was believable.
+fn plausible_response_time(
+    claimed: ResponseTimeMs,
+    server_elapsed: Duration,
+    round_trip: Duration,
+) -> (ResponseTimeMs, bool) {
+    let as_ms = |duration: Duration| -> ResponseTimeMs {
+        duration
+            .as_millis()
+            .try_into()
+            .unwrap_or(ResponseTimeMs::MAX)
+    };
+    let slowest = as_ms(server_elapsed);
+    let fastest = as_ms(
+        server_elapsed
+            .saturating_sub(round_trip + RESPONSE_TIME_TOLERANCE)
+            .max(MIN_REACTION_TIME),
+    )
+    .min(slowest);
+    // Claiming to be slower than you were only hurts yourself, so we don't
+    // treat it as suspicious
+    (claimed.clamp(fastest, slowest), claimed >= fastest)
 }
 
 fn get_fastest_response(
-    messages: &mut Vec<InputMessageType>,
+    messages: &mut [InputMessageType],
 ) -> Option<(PlayerNumber, &InputMessageType)> {
     messages
         .iter()
@@ -299,3 +823,406 @@ fn get_fastest_response(
             }
         })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use manager::Game;
+
+    fn draw(sender: PlayerNumber, received_at: Instant) -> InputMessage {
+        respond(sender, InputMessageType::Draw(100), received_at)
+    }
+
+    fn respond(
+        sender: PlayerNumber,
+        message: InputMessageType,
+        received_at: Instant,
+    ) -> InputMessage {
+        message::InputMessage {
+            sender,
+            message,
+            received_at,
+            round_trip: Some(Duration::from_millis(100)),
+        }
+    }
+
+    /// A response with the claimed time, arriving a realistic time after `drawn_at`
+    fn honest_response(
+        sender: PlayerNumber,
+        message: InputMessageType,
+        drawn_at: Instant,
+    ) -> InputMessage {
+        let claimed = message.response_time().unwrap_or(0);
+        let received_at = drawn_at + Duration::from_millis(u64::from(claimed) + 100);
+        respond(sender, message, received_at)
+    }
+
+    fn card(value: cards::Value) -> cards::Card {
+        cards::Card {
+            suit: cards::Suit::Clubs,
+            value,
+        }
+    }
+
+    /// A game with no draw cooldown and the given hands. The last card in
+    /// each hand is drawn first.
+    fn game_with_hands(hands: Vec<Vec<cards::Card>>) -> Snap {
+        let mut game = Snap::new(SnapConfig {
+            num_players: hands.len(),
+            draw_cooldown: Duration::ZERO,
+            ..SnapConfig::default()
+        });
+        for (player, hand) in game.players.iter_mut().zip(hands) {
+            player.hand = cards::CardPile::from(hand);
+        }
+        game
+    }
+
+    fn is_draw_too_soon(messages: &[OutputMessage]) -> bool {
+        messages
+            .iter()
+            .any(|m| matches!(m.message, OutputMessageType::DrawTooSoon { .. }))
+    }
+
+    #[test]
+    fn draw_cooldown_enforced() {
+        let mut game = Snap::new(SnapConfig {
+            draw_cooldown: Duration::from_secs(1),
+            ..SnapConfig::default()
+        });
+        let start = Instant::now();
+        let responses = game.player_action(draw(0, start));
+        assert!(!is_draw_too_soon(&responses));
+
+        let responses = game.player_action(draw(1, start + Duration::from_millis(400)));
+        let [response] = responses.as_slice() else {
+            panic!()
+        };
+        assert_eq!(response.recipient, 1);
+        let OutputMessageType::DrawTooSoon { wait_ms } = response.message else {
+            panic!()
+        };
+        assert_eq!(wait_ms, 600);
+
+        let responses = game.player_action(draw(1, start + Duration::from_secs(1)));
+        assert!(!is_draw_too_soon(&responses));
+    }
+
+    #[test]
+    fn invalid_actions_rejected() {
+        use cards::Value::*;
+        let rejection = |responses: Vec<OutputMessage>| match responses.as_slice() {
+            [
+                message::OutputMessage {
+                    recipient: 1,
+                    message: OutputMessageType::Rejected(error),
+                },
+            ] => Some(*error),
+            _ => None,
+        };
+        let mut game = game_with_hands(vec![vec![card(Five), card(Two)], vec![card(Two)]]);
+        let now = Instant::now();
+        assert_eq!(
+            rejection(game.player_action(respond(1, InputMessageType::NoResponse, now))),
+            Some(message::ProtocolError::NotYourTurn)
+        );
+        assert_eq!(
+            rejection(game.player_action(draw(1, now))),
+            Some(message::ProtocolError::NotYourTurn)
+        );
+
+        game.player_action(draw(0, now));
+        game.player_action(draw(1, now));
+        assert!(game.snap_possible());
+        game.player_action(honest_response(1, InputMessageType::Snap(200), now));
+        assert_eq!(
+            rejection(game.player_action(honest_response(1, InputMessageType::Snap(100), now))),
+            Some(message::ProtocolError::DuplicateResponse)
+        );
+
+        // Player 1 wins the snap, and with it the game
+        game.player_action(honest_response(0, InputMessageType::NoResponse, now));
+        assert!(game.has_ended());
+        assert_eq!(
+            rejection(game.player_action(draw(1, now))),
+            Some(message::ProtocolError::GameEnded)
+        );
+    }
+
+    #[test]
+    fn deal_for_each_player() {
+        for num_players in MIN_PLAYERS..=MAX_PLAYERS {
+            let game = Snap::new(SnapConfig {
+                num_players,
+                ..SnapConfig::default()
+            });
+            assert_eq!(game.players.len(), num_players);
+        }
+    }
+
+    #[test]
+    fn same_seed_same_shuffles() {
+        let hands = |game: &Snap| -> Vec<String> {
+            game.players
+                .iter()
+                .flat_map(|player| player.hand.cards().iter().map(|card| card.to_string()))
+                .collect()
+        };
+        let config = SnapConfig {
+            seed: Some(42),
+            ..SnapConfig::default()
+        };
+        let mut first = Snap::new(config.clone());
+        let mut second = Snap::new(config);
+        assert_eq!(first.seed(), 42);
+        assert_eq!(hands(&first), hands(&second));
+
+        for game in [&mut first, &mut second] {
+            let card = game.players[0].hand.draw().unwrap();
+            game.center_pile.place(card);
+            game.player_takes_center(1);
+        }
+        assert_eq!(hands(&first), hands(&second));
+    }
+
+    #[test]
+    fn turn_skips_empty_hands() {
+        use cards::Value::*;
+        let game = game_with_hands(vec![vec![card(Two)], vec![], vec![card(Three)]]);
+        assert_eq!(game.next_player_turn(), 2);
+    }
+
+    #[test]
+    fn snap_losers_share_center() {
+        use cards::Value::*;
+        let mut game = game_with_hands(vec![
+            vec![card(Five), card(Two)],
+            vec![card(Six), card(Two)],
+            vec![card(Ace), card(King)],
+        ]);
+        let now = Instant::now();
+        game.player_action(draw(0, now));
+        game.player_action(draw(1, now));
+        assert!(game.snap_possible());
+
+        game.player_action(honest_response(2, InputMessageType::Snap(200), now));
+        game.player_action(honest_response(0, InputMessageType::Snap(300), now));
+        let responses = game.player_action(respond(1, InputMessageType::NoResponse, now));
+
+        let taken: Vec<(PlayerNumber, usize)> = responses
+            .iter()
+            .filter(|m| m.recipient == 0)
+            .filter_map(|m| match m.message {
+                OutputMessageType::PlayerTakesCards { player, count } => Some((player, count)),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(taken, vec![(0, 1), (1, 1)]);
+        assert_eq!(game.player_turn, 0);
+        assert!(game.center_pile.is_empty());
+    }
+
+    #[test]
+    fn empty_handed_loser_dealt_first() {
+        use cards::Value::*;
+        let mut game = game_with_hands(vec![
+            vec![card(Five), card(Two)],
+            vec![card(Two)],
+            vec![card(Ace), card(King)],
+            vec![card(Ace), card(Queen)],
+        ]);
+        let now = Instant::now();
+        game.player_action(draw(0, now));
+        game.player_action(draw(1, now));
+        assert!(game.players[1].hand.is_empty());
+
+        game.player_action(honest_response(2, InputMessageType::Snap(200), now));
+        for player in [0, 1] {
+            game.player_action(respond(player, InputMessageType::NoResponse, now));
+        }
+        let responses = game.player_action(respond(3, InputMessageType::NoResponse, now));
+
+        // Two cards for three losers: Player 1 gets one of them
+        assert_eq!(game.players[1].hand.len(), 1);
+        assert!(
+            !responses
+                .iter()
+                .any(|m| matches!(m.message, OutputMessageType::PlayerWins(_)))
+        );
+    }
+
+    /// Two players, where the second draw makes a snap possible
+    fn game_with_snap(now: Instant) -> Snap {
+        use cards::Value::*;
+        let mut game = game_with_hands(vec![
+            vec![card(Five), card(Two)],
+            vec![card(Six), card(Two)],
+        ]);
+        game.player_action(draw(0, now));
+        game.player_action(draw(1, now));
+        game
+    }
+
+    #[test]
+    fn response_window_fills_in_no_response() {
+        let now = Instant::now();
+        let mut game = game_with_snap(now);
+        assert_eq!(game.next_deadline(), Some(now + DEFAULT_RESPONSE_WINDOW));
+
+        game.player_action(honest_response(0, InputMessageType::Snap(200), now));
+        assert!(game.tick(now + Duration::from_secs(1)).is_empty());
+
+        let responses = game.tick(now + DEFAULT_RESPONSE_WINDOW);
+        assert!(responses.iter().any(|m| matches!(
+            m.message,
+            OutputMessageType::OtherPlayerResponded {
+                player: 1,
+                msg: InputMessageType::NoResponse,
+                ..
+            }
+        )));
+        assert!(
+            responses
+                .iter()
+                .any(|m| matches!(m.message, OutputMessageType::PlayerTakesCenter(1)))
+        );
+        assert_eq!(game.next_deadline(), None);
+    }
+
+    #[test]
+    fn snap_after_window_is_a_mistake() {
+        let now = Instant::now();
+        let mut game = game_with_snap(now);
+        let responses = game.tick(now + DEFAULT_RESPONSE_WINDOW);
+        assert!(
+            !responses
+                .iter()
+                .any(|m| matches!(m.message, OutputMessageType::PlayerTakesCenter(_)))
+        );
+        assert!(!game.snap_possible());
+
+        let responses = game.player_action(respond(1, InputMessageType::Snap(5000), now));
+        assert!(responses.iter().any(|m| matches!(
+            m.message,
+            OutputMessageType::OtherPlayerResponded {
+                is_mistake: true,
+                ..
+            }
+        )));
+    }
+
+    #[test]
+    fn stats_sent_when_round_won() {
+        let now = Instant::now();
+        let later = |secs| now + Duration::from_secs(secs);
+        let mut game = game_with_snap(now);
+        game.player_action(honest_response(0, InputMessageType::Snap(200), now));
+        game.player_action(respond(1, InputMessageType::NoResponse, now));
+        game.player_action(draw(1, later(1)));
+        game.player_action(respond(1, InputMessageType::Snap(100), later(1)));
+        game.player_action(draw(1, later(2)));
+        assert_eq!(game.round_stats(), None);
+
+        let responses = game.player_action(draw(0, later(3)));
+        let stats = OutputMessageType::GameStats(vec![
+            PlayerStats {
+                snaps_won: 1,
+                false_snaps: 0,
+                draws: 2,
+                average_reaction_ms: Some(200),
+                largest_pile_taken: 0,
+            },
+            PlayerStats {
+                snaps_won: 0,
+                false_snaps: 1,
+                draws: 3,
+                average_reaction_ms: None,
+                largest_pile_taken: 2,
+            },
+        ]);
+        assert!(
+            responses
+                .iter()
+                .any(|m| m.recipient == 1 && m.message == stats)
+        );
+        assert_eq!(
+            game.round_stats(),
+            Some(RoundStats {
+                winner: 0,
+                duration: Duration::from_secs(3),
+                snaps: 1,
+                mistakes: 1,
+            })
+        );
+    }
+
+    #[test]
+    fn response_times_clamped_to_plausible_range() {
+        let round_trip = Duration::from_millis(100);
+        let elapsed = Duration::from_millis(600);
+        assert_eq!(
+            plausible_response_time(450, elapsed, round_trip),
+            (450, true)
+        );
+        assert_eq!(
+            plausible_response_time(0, elapsed, round_trip),
+            (400, false)
+        );
+        assert_eq!(
+            plausible_response_time(900, elapsed, round_trip),
+            (600, true)
+        );
+        // Nobody is faster than the minimum reaction time, however quick the
+        // connection
+        assert_eq!(
+            plausible_response_time(20, Duration::from_millis(150), round_trip),
+            (100, false)
+        );
+    }
+
+    #[test]
+    fn faked_response_time_loses_race() {
+        let now = Instant::now();
+        let mut game = game_with_snap(now);
+        // Player 0 claims an instant snap, but their response arrives late
+        game.player_action(respond(
+            0,
+            InputMessageType::Snap(0),
+            now + Duration::from_millis(900),
+        ));
+        let responses = game.player_action(honest_response(1, InputMessageType::Snap(400), now));
+        assert!(
+            responses
+                .iter()
+                .any(|m| matches!(m.message, OutputMessageType::PlayerTakesCenter(0)))
+        );
+        assert_eq!(game.players[0].suspicious_responses, 1);
+        assert!(game.log().entries.iter().any(|entry| matches!(
+            entry.event,
+            LogEvent::SuspiciousResponse {
+                player: 0,
+                claimed: 0,
+                flagged: false,
+                ..
+            }
+        )));
+    }
+
+    #[test]
+    fn snapshot_shows_pending_responses() {
+        let now = Instant::now();
+        let mut game = game_with_snap(now);
+        game.player_action(honest_response(1, InputMessageType::Snap(300), now));
+
+        let state = game.snapshot();
+        assert_eq!(state.hand_sizes, vec![1, 1]);
+        assert_eq!(state.center_pile.len(), 2);
+        assert_eq!(state.player_turn, 0);
+        assert!(matches!(
+            state.pending_responses.as_slice(),
+            [None, Some(InputMessageType::Snap(300))]
+        ));
+        assert!(state.awaiting_responses);
+        assert_eq!(state.winner, None);
+    }
+}
@@ -0,0 +1,178 @@
+use serde::{Deserialize, Serialize};
+
+use super::cards::{CardPile, Value};
+
+/// House rules deciding which cards can be snapped. A snap is possible if any
+/// of the enabled rules match the top of the center pile.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct SnapRules {
+    /// Top two cards have the same value
+    pub pairs: bool,
+    /// Top card has the same value as the third card down
+    pub sandwich: bool,
+    /// Top two cards have the same suit
+    pub same_suit: bool,
+    /// Top two cards add up to ten, counting aces as one
+    pub sum_to_ten: bool,
+    /// Top card is this value
+    pub named_card: Option<Value>,
+}
+
+impl Default for SnapRules {
+    fn default() -> Self {
+        Self {
+            pairs: true,
+            sandwich: false,
+            same_suit: false,
+            sum_to_ten: false,
+            named_card: None,
+        }
+    }
+}
+
+impl SnapRules {
+    pub fn validate(&self) -> Result<(), String> {
+        let any_enabled = self.pairs
+            || self.sandwich
+            || self.same_suit
+            || self.sum_to_ten
+            || self.named_card.is_some();
+        if !any_enabled {
+            return Err("At least one snap rule must be enabled".to_owned());
+        }
+        Ok(())
+    }
+
+    pub fn snap_possible(&self, pile: &CardPile) -> bool {
+        let Some(top) = pile.last() else {
+            return false;
+        };
+        if self.named_card == Some(top.value) {
+            return true;
+        }
+        if let Some(second) = pile.penultimate() {
+            if self.pairs && top.value == second.value {
+                return true;
+            }
+            if self.same_suit && top.suit == second.suit {
+                return true;
+            }
+            if self.sum_to_ten
+                && let (Some(a), Some(b)) = (top.value.pips(), second.value.pips())
+                && a + b == 10
+            {
+                return true;
+            }
+        }
+        if self.sandwich
+            && let Some(third) = pile.antepenultimate()
+            && top.value == third.value
+        {
+            return true;
+        }
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::game::cards::{Card, Suit};
+
+    fn pile(cards: &[(Value, Suit)]) -> CardPile {
+        CardPile::from(
+            cards
+                .iter()
+                .map(|&(value, suit)| Card { value, suit })
+                .collect::<Vec<Card>>(),
+        )
+    }
+
+    fn only(rules: SnapRules) -> SnapRules {
+        SnapRules {
+            pairs: false,
+            ..rules
+        }
+    }
+
+    #[test]
+    fn pairs() {
+        let rules = SnapRules::default();
+        assert!(rules.snap_possible(&pile(&[
+            (Value::Four, Suit::Clubs),
+            (Value::Four, Suit::Hearts),
+        ])));
+        assert!(!rules.snap_possible(&pile(&[
+            (Value::Four, Suit::Clubs),
+            (Value::Five, Suit::Clubs),
+        ])));
+    }
+
+    #[test]
+    fn sandwich() {
+        let rules = only(SnapRules {
+            sandwich: true,
+            ..SnapRules::default()
+        });
+        assert!(rules.snap_possible(&pile(&[
+            (Value::Four, Suit::Clubs),
+            (Value::King, Suit::Spades),
+            (Value::Four, Suit::Hearts),
+        ])));
+        assert!(!rules.snap_possible(&pile(&[
+            (Value::Four, Suit::Clubs),
+            (Value::Four, Suit::Hearts),
+        ])));
+    }
+
+    #[test]
+    fn same_suit() {
+        let rules = only(SnapRules {
+            same_suit: true,
+            ..SnapRules::default()
+        });
+        assert!(rules.snap_possible(&pile(&[
+            (Value::Four, Suit::Clubs),
+            (Value::Nine, Suit::Clubs),
+        ])));
+        assert!(!rules.snap_possible(&pile(&[
+            (Value::Four, Suit::Clubs),
+            (Value::Four, Suit::Hearts),
+        ])));
+    }
+
+    #[test]
+    fn sum_to_ten() {
+        let rules = only(SnapRules {
+            sum_to_ten: true,
+            ..SnapRules::default()
+        });
+        assert!(rules.snap_possible(&pile(&[
+            (Value::Ace, Suit::Clubs),
+            (Value::Nine, Suit::Hearts),
+        ])));
+        assert!(!rules.snap_possible(&pile(&[
+            (Value::Ten, Suit::Clubs),
+            (Value::Jack, Suit::Hearts),
+        ])));
+    }
+
+    #[test]
+    fn named_card() {
+        let rules = only(SnapRules {
+            named_card: Some(Value::Queen),
+            ..SnapRules::default()
+        });
+        assert!(rules.snap_possible(&pile(&[(Value::Queen, Suit::Diamonds)])));
+        assert!(!rules.snap_possible(&pile(&[
+            (Value::Queen, Suit::Diamonds),
+            (Value::Two, Suit::Diamonds),
+        ])));
+    }
+
+    #[test]
+    fn no_rules_rejected() {
+        assert!(only(SnapRules::default()).validate().is_err());
+    }
+}
@@ -1,51 +1,340 @@
-use std::sync::Arc;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
+use clap::Parser;
 use papaya::{HashMap, OccupiedError};
 use serde::{Deserialize, Serialize};
+use tokio::io::AsyncWriteExt;
 
 use warp::Filter;
 
+mod assets;
+mod bot;
+mod config;
 mod game;
 mod manager;
+mod matchmaking;
 mod message;
+mod metrics;
+mod storage;
 mod websocket;
 
-const MAX_NUM_GAMES: usize = 1000;
+const QUICKPLAY_BOT_DIFFICULTY: bot::Difficulty = bot::Difficulty::Medium;
 
-type SnapManager = manager::SessionManager<game::Snap>;
+/// How often to check the quickplay queue for players that have waited too long
+const QUICKPLAY_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Number of players shown on the leaderboard
+const LEADERBOARD_SIZE: usize = 20;
+
+/// How often to check whether every game has finished while shutting down
+const SHUTDOWN_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Time given to websockets to say goodbye once closed, before the server exits
+const SHUTDOWN_CLOSE_TIME: Duration = Duration::from_millis(250);
+
+type SnapManager = manager::SessionManager<game::SnapMatch>;
 type WebSocketHandler = websocket::WebSocketHandler<InputMessageType, OutputMessageType>;
-type WebSocketMap = HashMap<usize, WebSocketHandler>;
+type ParticipantMap = HashMap<usize, Participant>;
+
+/// Whoever is sitting in a seat: A person connected through a websocket, or a
+/// bot running on the server
+enum Participant {
+    Player(WebSocketHandler),
+    Bot(bot::BotHandler),
+}
+
+impl Participant {
+    fn send(&self, message: OutputMessageType) -> Result<(), websocket::SendError> {
+        match self {
+            Participant::Player(ws_handler) => ws_handler.send(message),
+            Participant::Bot(bot) => match message {
+                OutputMessageType::GameUpdate(update) => {
+                    bot.send(update).map_err(|()| websocket::SendError::Closed)
+                }
+                // The game may be ready for the bot's move
+                OutputMessageType::GameStarted { .. }
+                | OutputMessageType::PlayerReconnected { .. } => {
+                    bot.nudge();
+                    Ok(())
+                }
+                _ => Ok(()),
+            },
+        }
+    }
+
+    /// Tell the participant their message was turned down
+    fn reject(&self, code: message::ProtocolError) {
+        match self {
+            Participant::Player(ws_handler) => _ = ws_handler.reject(code),
+            // Bots can't tell the game is paused, but otherwise they play by
+            // the rules; if they don't, it's a bug
+            Participant::Bot(_) if code == message::ProtocolError::GamePaused => {}
+            Participant::Bot(_) => println!("Bot broke the rules: {}", code),
+        }
+    }
+
+    fn round_trip(&self) -> Option<Duration> {
+        match self {
+            Participant::Player(ws_handler) => ws_handler.round_trip(),
+            Participant::Bot(bot) => bot.round_trip(),
+        }
+    }
+
+    fn close(&self) {
+        match self {
+            Participant::Player(ws_handler) => ws_handler.close(),
+            Participant::Bot(bot) => bot.close(),
+        }
+    }
+}
 
 // Input / output messages
 struct ServerState {
     manager: SnapManager,
-    users: WebSocketMap,
+    users: ParticipantMap,
+    /// Settings for new games, before any options chosen by the creator
+    game_defaults: game::SnapConfig,
+    /// How long a player who loses their connection has to rejoin before
+    /// their game is destroyed
+    reconnect_grace_period: Duration,
+    quickplay: matchmaking::MatchQueue<QueuedPlayer>,
+    /// How long players wait in the quickplay queue for someone to play with
+    quickplay_timeout: Duration,
+    /// How long quickplay waits before giving up on people and seating a bot
+    /// instead, if ever
+    quickplay_bot_after: Option<Duration>,
+    /// Profiles and game records, if the database could be opened
+    storage: Option<Arc<storage::Storage>>,
+    /// Where logs of finished games are kept
+    game_log_dir: PathBuf,
+    /// Names players chose to play under, by user ID. Players without one are
+    /// anonymous, and don't get a profile.
+    names: HashMap<usize, String>,
+    metrics: metrics::Metrics,
+    /// Whether the server is taking new players
+    ready: AtomicBool,
+    /// How often websockets are pinged, and when to give up on them
+    heartbeat: websocket::Heartbeat,
+    /// What to do when a player falls behind with their messages. Spectators
+    /// are always caught up with the state of the table instead.
+    overflow: websocket::OverflowPolicy,
+    /// How long running games get to finish once the server is told to stop
+    shutdown_drain: Duration,
+    /// When the remaining games will be closed, once the server is shutting
+    /// down
+    shutdown_at: OnceLock<Instant>,
+}
+
+/// Someone waiting in the quickplay queue
+struct QueuedPlayer {
+    ws_handler: WebSocketHandler,
+    name: Option<String>,
+    /// Their place in the queue, kept in case they have to be put back in it
+    ticket: matchmaking::Ticket,
+    joined_at: Instant,
+    /// Set to the player's user ID once they have a seat, or `None` if they
+    /// leave the queue first
+    seat: Arc<OnceLock<Option<usize>>>,
+}
+
+/// Options chosen by the player creating a game, through the query string
+#[derive(Debug, Deserialize)]
+struct CreateOptions {
+    players: Option<usize>,
+    pairs: Option<bool>,
+    sandwich: Option<bool>,
+    same_suit: Option<bool>,
+    sum_to_ten: Option<bool>,
+    named_card: Option<game::cards::Value>,
+    /// Number of rounds in the match
+    best_of: Option<usize>,
+    /// Fill the other seats with bots instead of waiting for people
+    bot: Option<bot::Difficulty>,
+    /// Name to play under
+    name: Option<String>,
+}
+
+/// Protocol version the client speaks and how it wants messages framed,
+/// through the query string of any websocket route
+#[derive(Debug, Deserialize)]
+struct ProtocolQuery {
+    protocol: Option<u32>,
+    encoding: Option<websocket::Encoding>,
+    /// Encoding offered through the `Sec-WebSocket-Protocol` header instead
+    #[serde(skip)]
+    subprotocol: Option<websocket::Encoding>,
+}
+
+impl ProtocolQuery {
+    /// The query string wins over the header, and JSON is the default
+    fn encoding(&self) -> websocket::Encoding {
+        self.encoding.or(self.subprotocol).unwrap_or_default()
+    }
+
+    /// The subprotocol to confirm in the handshake, if one was offered and
+    /// we're using it
+    fn accepted_subprotocol(&self) -> Option<websocket::Encoding> {
+        self.subprotocol
+            .filter(|&offered| offered == self.encoding())
+    }
+}
+
+/// Options chosen by a player joining a game, through the query string
+#[derive(Debug, Deserialize)]
+struct JoinOptions {
+    /// Name to play under
+    name: Option<String>,
+}
+
+impl CreateOptions {
+    /// Apply these options on top of the server's defaults
+    fn apply(&self, config: &mut game::SnapConfig) {
+        if let Some(num_players) = self.players {
+            config.num_players = num_players;
+        }
+        let rules = &mut config.rules;
+        rules.pairs = self.pairs.unwrap_or(rules.pairs);
+        rules.sandwich = self.sandwich.unwrap_or(rules.sandwich);
+        rules.same_suit = self.same_suit.unwrap_or(rules.same_suit);
+        rules.sum_to_ten = self.sum_to_ten.unwrap_or(rules.sum_to_ten);
+        rules.named_card = self.named_card.or(rules.named_card);
+        config.best_of = self.best_of.unwrap_or(config.best_of);
+    }
 }
 
 type OutputMessage = message::OutputMessage<usize, OutputMessageType>;
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Clone, Debug, Deserialize, Serialize)]
 enum OutputMessageType {
-    GameCreated { other_player_id: usize },
+    GameCreated {
+        /// Protocol version the server is speaking
+        protocol_version: u32,
+        /// Codes for the other seats, to share with the other players
+        join_codes: Vec<manager::JoinCode>,
+        rejoin_code: manager::JoinCode,
+        /// Code for anyone who wants to watch the game
+        watch_code: manager::JoinCode,
+    },
     GameDestroyed,
     ServerFull,
+    InvalidSettings(String),
     UserAlreadyConnected,
     GameNotFound,
-    GameStarted { your_number: game::PlayerNumber },
+    GameStarted {
+        /// Protocol version the server is speaking
+        protocol_version: u32,
+        your_number: game::PlayerNumber,
+        /// Seats at the table
+        num_players: usize,
+        rules: game::SnapRules,
+        rejoin_code: manager::JoinCode,
+        watch_code: manager::JoinCode,
+    },
+    /// Now watching a game. The current state of the table follows.
+    Watching {
+        protocol_version: u32,
+        rules: game::SnapRules,
+    },
+    /// The client speaks a protocol version the server doesn't, and should
+    /// be reloaded. The server speaks every version from `min_protocol_version`
+    /// to `protocol_version`.
+    IncompatibleVersion {
+        protocol_version: u32,
+        min_protocol_version: u32,
+    },
+    /// Game is paused until this player rejoins or the grace period runs out
+    PlayerDisconnected {
+        player: game::PlayerNumber,
+        grace_period_ms: u64,
+    },
+    PlayerReconnected {
+        player: game::PlayerNumber,
+    },
+    /// Place in the quickplay queue, counting from 1
+    QueuePosition {
+        position: usize,
+    },
+    /// Nobody turned up to play in time
+    QueueTimedOut,
+    /// The server is stopping, and will close any games still running after
+    /// this long. New games are turned away.
+    ServerShuttingDown {
+        closing_in_ms: u64,
+    },
+    /// The client's last message was turned down. Clients that keep sending
+    /// messages the server can't read are disconnected.
+    ProtocolError {
+        code: message::ProtocolError,
+    },
     GameUpdate(game::OutputMessageType),
 }
 
+impl From<message::ProtocolError> for OutputMessageType {
+    fn from(code: message::ProtocolError) -> Self {
+        OutputMessageType::ProtocolError { code }
+    }
+}
+
 #[derive(Debug, Deserialize, Serialize)]
 enum InputMessageType {
     GameUpdate(game::InputMessageType),
+    /// Client wants everything on the table, e.g. because it's lost track
+    RequestState,
+}
+
+impl InputMessageType {
+    /// Name of the message, for metrics
+    fn kind(&self) -> &'static str {
+        match self {
+            InputMessageType::GameUpdate(message) => match message {
+                game::InputMessageType::Draw(_) => "Draw",
+                game::InputMessageType::Snap(_) => "Snap",
+                game::InputMessageType::NoResponse => "NoResponse",
+                game::InputMessageType::PlayAgain => "PlayAgain",
+            },
+            InputMessageType::RequestState => "RequestState",
+        }
+    }
 }
 
 #[tokio::main]
 async fn main() {
+    let cli = config::Cli::parse();
+    if let Some(config::Command::Replay { log }) = &cli.command {
+        replay_game_log(log);
+        return;
+    }
+    let config = match cli.server_config() {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("Invalid settings: {}", e);
+            std::process::exit(1);
+        }
+    };
+
     let server_state = Arc::new(ServerState {
-        manager: SnapManager::new(MAX_NUM_GAMES),
-        users: WebSocketMap::default(),
+        manager: SnapManager::new(config.max_games),
+        users: ParticipantMap::default(),
+        game_defaults: config.game.snap_config(),
+        reconnect_grace_period: config.reconnect_grace_period(),
+        quickplay: matchmaking::MatchQueue::new(),
+        quickplay_timeout: config.quickplay_timeout(),
+        quickplay_bot_after: config.quickplay_bot_after(),
+        storage: open_storage(&config.database),
+        game_log_dir: config.game_log_dir.clone(),
+        names: HashMap::default(),
+        metrics: metrics::Metrics::default(),
+        ready: AtomicBool::new(false),
+        heartbeat: config.heartbeat(),
+        overflow: config.overflow_policy(),
+        shutdown_drain: config.shutdown_drain(),
+        shutdown_at: OnceLock::new(),
     });
+    tokio::task::spawn(watch_quickplay_queue(server_state.clone()));
+    let shutdown_state = server_state.clone();
 
     let state = move || {
         let cloned = server_state.clone();
@@ -53,53 +342,185 @@ async fn main() {
     };
 
     // Route to create a new game
-    let create = warp::path!("create").and(warp::ws()).and(state()).map(
-        |ws: warp::ws::Ws, state: Arc<ServerState>| {
-            // This will call our function if the handshake succeeds.
-            ws.on_upgrade(move |socket| create(socket, state))
-        },
-    );
+    let create = warp::path!("create")
+        .and(warp::query::<CreateOptions>())
+        .and(protocol_query())
+        .and(warp::ws())
+        .and(state())
+        .map(
+            |options: CreateOptions,
+             protocol: ProtocolQuery,
+             ws: warp::ws::Ws,
+             state: Arc<ServerState>| {
+                // This will call our function if the handshake succeeds.
+                upgrade(ws, protocol, move |socket, protocol| {
+                    create(options, protocol, socket, state)
+                })
+            },
+        );
 
-    let join = warp::path!("join" / usize)
+    let join = warp::path!("join" / String)
+        .and(warp::query::<JoinOptions>())
+        .and(protocol_query())
         .and(warp::ws())
         .and(state())
         .map(
-            |user_id: usize, ws: warp::ws::Ws, state: Arc<ServerState>| {
+            |join_code: String,
+             options: JoinOptions,
+             protocol: ProtocolQuery,
+             ws: warp::ws::Ws,
+             state: Arc<ServerState>| {
                 // This will call our function if the handshake succeeds.
-                ws.on_upgrade(move |socket| join(user_id, socket, state))
+                upgrade(ws, protocol, move |socket, protocol| {
+                    join(join_code, options, protocol, socket, state)
+                })
             },
         );
 
-    let index = warp::path::end().and(
-        warp::fs::file("../frontend/index.html")
-    );
-    let index_js = warp::path("main.js").and(
-        warp::fs::file("../frontend/main.js")
-    );
-    let index_css = warp::path("main.css").and(
-        warp::fs::file("../frontend/main.css")
-    );
-    let images = warp::path("snap").and(warp::path("images")).and(
-        warp::fs::dir ("../frontend/images")
-    );
-    let routes = index.or(index_js).or(index_css).or(images).or(create).or(join);
+    // Route to watch a game without taking a seat
+    let watch = warp::path!("watch" / String)
+        .and(protocol_query())
+        .and(warp::ws())
+        .and(state())
+        .map(
+            |watch_code: String,
+             protocol: ProtocolQuery,
+             ws: warp::ws::Ws,
+             state: Arc<ServerState>| {
+                // This will call our function if the handshake succeeds.
+                upgrade(ws, protocol, move |socket, protocol| {
+                    watch(watch_code, protocol, socket, state)
+                })
+            },
+        );
+
+    // Route to be matched up with whoever else is waiting
+    let quickplay = warp::path!("quickplay")
+        .and(warp::query::<JoinOptions>())
+        .and(protocol_query())
+        .and(warp::ws())
+        .and(state())
+        .map(
+            |options: JoinOptions,
+             protocol: ProtocolQuery,
+             ws: warp::ws::Ws,
+             state: Arc<ServerState>| {
+                // This will call our function if the handshake succeeds.
+                upgrade(ws, protocol, move |socket, protocol| {
+                    quickplay(options, protocol, socket, state)
+                })
+            },
+        );
+
+    // Route for the best rated players, as JSON
+    let leaderboard = warp::path!("leaderboard")
+        .and(warp::get())
+        .and(state())
+        .then(leaderboard);
+
+    // Routes for the hosting platform to check on the server
+    let healthz = warp::path!("healthz").and(warp::get()).map(|| "ok");
+    let readyz =
+        warp::path!("readyz")
+            .and(warp::get())
+            .and(state())
+            .map(|state: Arc<ServerState>| {
+                if state.ready.load(Ordering::Relaxed) {
+                    warp::reply::with_status("ready", warp::http::StatusCode::OK)
+                } else {
+                    warp::reply::with_status(
+                        "not ready",
+                        warp::http::StatusCode::SERVICE_UNAVAILABLE,
+                    )
+                }
+            });
+    let metrics = warp::path!("metrics")
+        .and(warp::get())
+        .and(state())
+        .then(metrics);
 
-    warp::serve(routes).run(([0, 0, 0, 0], 3030)).await;
+    let frontend = assets::routes(&config.static_dir);
+    let routes = frontend
+        .or(create)
+        .or(join)
+        .or(quickplay)
+        .or(watch)
+        .or(leaderboard)
+        .or(healthz)
+        .or(readyz)
+        .or(metrics);
+
+    let address = (config.bind_address, config.port);
+    let (_, server) = match warp::serve(routes)
+        .try_bind_with_graceful_shutdown(address, shut_down(shutdown_state.clone()))
+    {
+        Ok(bound) => bound,
+        Err(e) => {
+            eprintln!("Could not listen on port {}: {}", config.port, e);
+            std::process::exit(1);
+        }
+    };
+    // Only ready once we're actually listening
+    shutdown_state.ready.store(true, Ordering::Relaxed);
+    server.await;
 }
 
-async fn create(ws: warp::ws::WebSocket, state: Arc<ServerState>) {
+async fn create(
+    options: CreateOptions,
+    protocol: ProtocolQuery,
+    ws: warp::ws::WebSocket,
+    state: Arc<ServerState>,
+) {
+    let encoding = protocol.encoding();
+    if let Some(notice) = refusal(&protocol, &state) {
+        send_message_and_close(ws, encoding, notice);
+        return;
+    }
+    let mut config = state.game_defaults.clone();
+    options.apply(&mut config);
+    let name = match config.validate().and_then(|_| chosen_name(&options.name)) {
+        Ok(name) => name,
+        Err(reason) => {
+            send_message_and_close(ws, encoding, OutputMessageType::InvalidSettings(reason));
+            return;
+        }
+    };
+
     println!("Creating new game");
-    match state.manager.create().await {
+    match state.manager.create(config).await {
         Ok(users) => {
-            let (this_user, other_user) = (users[0], users[1]);
-            let ws_handler = create_linked_websocket(this_user, ws, &state);
-            match state.users.pin().try_insert(this_user, ws_handler) {
+            state.metrics.game_created();
+            let this_user = users[0];
+            let (Ok(connection), Ok(join_codes), Ok(watch_code)) = (
+                state.manager.connect(this_user, Instant::now()).await,
+                state.manager.join_codes(this_user).await,
+                state.manager.watch_code(this_user).await,
+            ) else {
+                // This should never happen
+                println!("Could not connect user to new game");
+                send_message_and_close(ws, encoding, OutputMessageType::GameNotFound);
+                return;
+            };
+            if let Some(name) = name {
+                state.names.pin().insert(this_user, name);
+            }
+            let ws_handler = create_linked_websocket(this_user, ws, encoding, &state);
+            match state
+                .users
+                .pin()
+                .try_insert(this_user, Participant::Player(ws_handler))
+            {
                 Ok(handler_ref) => {
                     // Let the user know the connection was successful and give them the
-                    // ID of the other player so they can connect.
-                    _ = handler_ref.send(OutputMessageType::GameCreated {
-                        other_player_id: other_user,
-                    });
+                    // codes for the other seats so the other players can connect.
+                    if options.bot.is_none() {
+                        _ = handler_ref.send(OutputMessageType::GameCreated {
+                            protocol_version: message::PROTOCOL_VERSION,
+                            join_codes: join_codes[1..].to_vec(),
+                            rejoin_code: connection.rejoin_code,
+                            watch_code,
+                        });
+                    }
                 }
                 Err(OccupiedError {
                     current: _,
@@ -108,45 +529,447 @@ async fn create(ws: warp::ws::WebSocket, state: Arc<ServerState>) {
                     // This should never happen
                     println!("Conflict with create user");
                     not_inserted.close();
+                    return;
                 }
             };
+
+            if let Some(difficulty) = options.bot {
+                for &bot_user in &users[1..] {
+                    add_bot(bot_user, difficulty, &state).await;
+                }
+                announce_game_started(this_user, &state).await;
+            }
         }
         Err(manager::CreateGameError::ServerFull) => {
-            send_message_and_close(ws, OutputMessageType::ServerFull);
+            state.metrics.server_full();
+            send_message_and_close(ws, encoding, OutputMessageType::ServerFull);
         }
     }
 }
 
-async fn join(user_id: usize, ws: warp::ws::WebSocket, state: Arc<ServerState>) {
-    let Ok(all_players_in_game) = state.manager.get_players(user_id).await else {
-        send_message_and_close(ws, OutputMessageType::GameNotFound);
+async fn join(
+    join_code: manager::JoinCode,
+    options: JoinOptions,
+    protocol: ProtocolQuery,
+    ws: warp::ws::WebSocket,
+    state: Arc<ServerState>,
+) {
+    let encoding = protocol.encoding();
+    if let Some(notice) = refusal(&protocol, &state) {
+        send_message_and_close(ws, encoding, notice);
         return;
+    }
+    let name = match chosen_name(&options.name) {
+        Ok(name) => name,
+        Err(reason) => {
+            send_message_and_close(ws, encoding, OutputMessageType::InvalidSettings(reason));
+            return;
+        }
     };
-    let users_map = state.users.pin();
-    match users_map.contains_key(&user_id) {
-        true => {
-            send_message_and_close(ws, OutputMessageType::UserAlreadyConnected);
+    let Some(user_id) = state.manager.find_seat(&join_code.to_uppercase()) else {
+        send_message_and_close(ws, encoding, OutputMessageType::GameNotFound);
+        return;
+    };
+    let (Ok(all_players_in_game), Ok(watch_code)) = (
+        state.manager.get_players(user_id).await,
+        state.manager.watch_code(user_id).await,
+    ) else {
+        send_message_and_close(ws, encoding, OutputMessageType::GameNotFound);
+        return;
+    };
+    let Ok((rules, snapshot, score)) = state
+        .manager
+        .inspect(user_id, |game| {
+            let round = game.round();
+            (round.rules().clone(), round.snapshot(), game.score())
+        })
+        .await
+    else {
+        send_message_and_close(ws, encoding, OutputMessageType::GameNotFound);
+        return;
+    };
+    let connection = match state.manager.connect(user_id, Instant::now()).await {
+        Ok(connection) => connection,
+        Err(manager::ConnectError::AlreadyConnected) => {
+            send_message_and_close(ws, encoding, OutputMessageType::UserAlreadyConnected);
+            return;
+        }
+        Err(manager::ConnectError::GameDoesNotExist) => {
+            send_message_and_close(ws, encoding, OutputMessageType::GameNotFound);
+            return;
+        }
+    };
+    if let Some(name) = name {
+        state.names.pin().insert(user_id, name);
+    }
+    let ws_handler = create_linked_websocket(user_id, ws, encoding, &state);
+    state
+        .users
+        .pin()
+        .insert(user_id, Participant::Player(ws_handler));
+
+    if connection.rejoined {
+        let spectators = state
+            .manager
+            .get_spectators(user_id)
+            .await
+            .unwrap_or_default();
+        let users_map = state.users.pin();
+        // Let everyone else know they're back
+        let others = all_players_in_game.iter().filter(|&&id| id != user_id);
+        for recipient in others.chain(spectators.iter()) {
+            if let Some(ws_handler) = users_map.get(recipient) {
+                _ = ws_handler.send(OutputMessageType::PlayerReconnected {
+                    player: connection.player,
+                });
+            }
+        }
+        // Catch them up if their game had already started
+        if connection.seats_filled
+            && let Some(ws_handler) = users_map.get(&user_id)
+        {
+            _ = ws_handler.send(OutputMessageType::GameStarted {
+                protocol_version: message::PROTOCOL_VERSION,
+                your_number: connection.player,
+                num_players: all_players_in_game.len(),
+                rules,
+                rejoin_code: connection.rejoin_code,
+                watch_code,
+            });
+            _ = ws_handler.send(OutputMessageType::GameUpdate(
+                game::OutputMessageType::GameState(snapshot),
+            ));
+            if let Some(score) = score {
+                _ = ws_handler.send(OutputMessageType::GameUpdate(
+                    game::OutputMessageType::MatchScore(score),
+                ));
+            }
+        }
+        if connection.resumed {
+            schedule_tick(user_id, state.clone());
+        }
+        return;
+    }
+
+    // Once every seat is taken, let everyone know the game has started
+    if connection.seats_filled {
+        announce_game_started(user_id, &state).await;
+    }
+}
+
+async fn quickplay(
+    options: JoinOptions,
+    protocol: ProtocolQuery,
+    ws: warp::ws::WebSocket,
+    state: Arc<ServerState>,
+) {
+    let encoding = protocol.encoding();
+    if let Some(notice) = refusal(&protocol, &state) {
+        send_message_and_close(ws, encoding, notice);
+        return;
+    }
+    let name = match chosen_name(&options.name) {
+        Ok(name) => name,
+        Err(reason) => {
+            send_message_and_close(ws, encoding, OutputMessageType::InvalidSettings(reason));
             return;
         }
-        false => {
-            let ws_handler = create_linked_websocket(user_id, ws, &state);
-            users_map.insert(user_id, ws_handler);
+    };
+    let ticket = state.quickplay.new_ticket();
+    let seat: Arc<OnceLock<Option<usize>>> = Arc::default();
+    let on_message = {
+        let cloned_state = state.clone();
+        let seat = seat.clone();
+        move |msg| {
+            let state = cloned_state.clone();
+            let seat = seat.clone();
+            async move {
+                // Anything sent before the game starts is ignored
+                if let Some(Some(user_id)) = seat.get() {
+                    handle_message(msg, *user_id, state).await;
+                }
+            }
+        }
+    };
+    let on_disconnect = {
+        let cloned_state = state.clone();
+        let seat = seat.clone();
+        move || {
+            let state = cloned_state.clone();
+            let seat = seat.clone();
+            async move {
+                if seat.set(None).is_ok() {
+                    // They were still waiting
+                    state.quickplay.leave(ticket).await;
+                    send_queue_positions(&state).await;
+                } else if let Some(Some(user_id)) = seat.get() {
+                    user_disconnected(*user_id, state).await;
+                }
+            }
+        }
+    };
+    let ws_handler = WebSocketHandler::new(
+        ws,
+        websocket::Peer::Queued(ticket),
+        state.heartbeat,
+        state.overflow,
+        encoding,
+        on_message,
+        on_disconnect,
+    );
+    let joined_at = Instant::now();
+    let player = QueuedPlayer {
+        ws_handler,
+        name,
+        ticket,
+        joined_at,
+        seat: seat.clone(),
+    };
+    state.quickplay.join(ticket, player, joined_at).await;
+    if seat.get() == Some(&None) {
+        // They left before they were in the queue, so leaving it did nothing
+        state.quickplay.leave(ticket).await;
+        return;
+    }
+
+    let num_players = state.game_defaults.num_players;
+    if let Some(players) = state.quickplay.take_match(num_players).await {
+        start_quickplay_game(players, &state).await;
+    }
+    send_queue_positions(&state).await;
+}
+
+/// Seat the players in a new game, filling any empty seats with bots if that's
+/// allowed. Otherwise, the players go back to the front of the queue.
+async fn start_quickplay_game(players: Vec<QueuedPlayer>, state: &Arc<ServerState>) {
+    // Anyone who has already left the queue would only hold up the others
+    let players: Vec<QueuedPlayer> = players
+        .into_iter()
+        .filter(|player| player.seat.get().is_none())
+        .collect();
+    if players.is_empty() {
+        return;
+    }
+    if players.len() < state.game_defaults.num_players && state.quickplay_bot_after.is_none() {
+        // Someone left while the game was being set up, and there are no bots
+        // to take their seat
+        requeue(players, state).await;
+        return;
+    }
+    let Ok(users) = state.manager.create(state.game_defaults.clone()).await else {
+        state.metrics.server_full();
+        for player in players {
+            _ = player.ws_handler.send(OutputMessageType::ServerFull);
+            player.ws_handler.close();
+        }
+        return;
+    };
+    state.metrics.game_created();
+    let num_people = players.len();
+    for (player, &user_id) in players.into_iter().zip(&users) {
+        let Ok(_) = state.manager.connect(user_id, Instant::now()).await else {
+            // This should never happen
+            println!("Could not connect quickplay user to new game");
+            continue;
+        };
+        let left_queue = player.seat.set(Some(user_id)).is_err();
+        if let Some(name) = player.name {
+            state.names.pin().insert(user_id, name);
+        }
+        state
+            .users
+            .pin()
+            .insert(user_id, Participant::Player(player.ws_handler));
+        if left_queue {
+            // They left while we were finding them a game
+            user_disconnected(user_id, state.clone()).await;
+        }
+    }
+    for &bot_user in &users[num_people..] {
+        add_bot(bot_user, QUICKPLAY_BOT_DIFFICULTY, state).await;
+    }
+    announce_game_started(users[0], state).await;
+}
+
+/// Put players back at the front of the quickplay queue, in the order they
+/// first joined it
+async fn requeue(players: Vec<QueuedPlayer>, state: &Arc<ServerState>) {
+    let places: Vec<_> = players
+        .iter()
+        .map(|player| (player.ticket, player.seat.clone()))
+        .collect();
+    let waiters = players
+        .into_iter()
+        .map(|player| (player.ticket, player.joined_at, player))
+        .collect();
+    state.quickplay.put_back(waiters).await;
+    for (ticket, seat) in places {
+        if seat.get() == Some(&None) {
+            // They left while out of the queue, so leaving it did nothing
+            state.quickplay.leave(ticket).await;
+        }
+    }
+}
+
+/// Let everyone in the quickplay queue know where they are in it
+async fn send_queue_positions(state: &Arc<ServerState>) {
+    state
+        .quickplay
+        .for_each_position(|player, position| {
+            _ = player
+                .ws_handler
+                .send(OutputMessageType::QueuePosition { position });
+        })
+        .await;
+}
+
+/// Deal with players that have waited too long for a quickplay game: Give them
+/// a bot to play, or send them away.
+async fn watch_quickplay_queue(state: Arc<ServerState>) {
+    let mut interval = tokio::time::interval(QUICKPLAY_CHECK_INTERVAL);
+    loop {
+        interval.tick().await;
+        let now = Instant::now();
+        let mut queue_changed = false;
+        if let Some(bot_after) = state.quickplay_bot_after {
+            for player in state.quickplay.take_waiting_for(bot_after, now).await {
+                start_quickplay_game(vec![player], &state).await;
+                queue_changed = true;
+            }
+        }
+        let timeout = state.quickplay_timeout;
+        for player in state.quickplay.take_waiting_for(timeout, now).await {
+            _ = player.ws_handler.send(OutputMessageType::QueueTimedOut);
+            player.ws_handler.close();
+            queue_changed = true;
+        }
+        if queue_changed {
+            send_queue_positions(&state).await;
+        }
+    }
+}
+
+async fn watch(
+    watch_code: manager::JoinCode,
+    protocol: ProtocolQuery,
+    ws: warp::ws::WebSocket,
+    state: Arc<ServerState>,
+) {
+    let encoding = protocol.encoding();
+    if let Some(notice) = refusal(&protocol, &state) {
+        send_message_and_close(ws, encoding, notice);
+        return;
+    }
+    let Ok((spectator, rules)) = state
+        .manager
+        .watch(&watch_code.to_uppercase(), |game| {
+            game.round().rules().clone()
+        })
+        .await
+    else {
+        send_message_and_close(ws, encoding, OutputMessageType::GameNotFound);
+        return;
+    };
+    let ws_handler = create_spectator_websocket(spectator, ws, encoding, &state);
+    _ = ws_handler.send(OutputMessageType::Watching {
+        protocol_version: message::PROTOCOL_VERSION,
+        rules,
+    });
+    state
+        .users
+        .pin()
+        .insert(spectator, Participant::Player(ws_handler));
+    // Anything sent before the spectator was added to the users map went
+    // nowhere, so show them the table only now
+    send_state(spectator, &state).await;
+}
 
-            // Let everyone know the game has started
-            for (your_number, player_id) in all_players_in_game.into_iter().enumerate() {
-                let Some(ws_handler) = users_map.get(&player_id) else { break; };
-                _ = ws_handler.send(OutputMessageType::GameStarted { your_number });
+/// Tell everyone in the user's game which seat is theirs, and how to get back
+/// to it
+async fn announce_game_started(user_id: usize, state: &Arc<ServerState>) {
+    let (Ok(players), Ok(join_codes), Ok(watch_code), Ok(rules)) = (
+        state.manager.get_players(user_id).await,
+        state.manager.join_codes(user_id).await,
+        state.manager.watch_code(user_id).await,
+        state
+            .manager
+            .inspect(user_id, |game| game.round().rules().clone())
+            .await,
+    ) else {
+        return;
+    };
+    let users_map = state.users.pin();
+    let num_players = players.len();
+    let seats = players.into_iter().zip(join_codes).enumerate();
+    for (your_number, (player_id, rejoin_code)) in seats {
+        let Some(participant) = users_map.get(&player_id) else {
+            // They'll find out when they rejoin
+            continue;
+        };
+        _ = participant.send(OutputMessageType::GameStarted {
+            protocol_version: message::PROTOCOL_VERSION,
+            your_number,
+            num_players,
+            rules: rules.clone(),
+            rejoin_code,
+            watch_code: watch_code.clone(),
+        });
+    }
+}
+
+/// Sit a bot in the user's seat
+async fn add_bot(user_id: usize, difficulty: bot::Difficulty, state: &Arc<ServerState>) {
+    let Ok(connection) = state.manager.connect(user_id, Instant::now()).await else {
+        return;
+    };
+    let bot = bot::Bot::new(connection.player, difficulty.config());
+    let bot_handler = create_linked_bot(user_id, bot, state);
+    state
+        .names
+        .pin()
+        .insert(user_id, difficulty.name().to_string());
+    state
+        .users
+        .pin()
+        .insert(user_id, Participant::Bot(bot_handler));
+}
+
+/// Start a bot playing as user_id. It plays through the same messages as
+/// people do.
+fn create_linked_bot(user_id: usize, bot: bot::Bot, state: &Arc<ServerState>) -> bot::BotHandler {
+    let on_message = {
+        let cloned_state = state.clone();
+        move |msg| {
+            handle_message(
+                InputMessageType::GameUpdate(msg),
+                user_id,
+                cloned_state.clone(),
+            )
+        }
+    };
+    let look = {
+        let cloned_state = state.clone();
+        move || {
+            let state = cloned_state.clone();
+            async move {
+                state
+                    .manager
+                    .inspect(user_id, |game| game.round().snapshot())
+                    .await
+                    .ok()
             }
         }
     };
+    bot::BotHandler::new(bot, on_message, look)
 }
 
 /// Create a websocket linked to the user_id's game. Incoming messages will from
-/// this websocket will affect the game, and closing the connection will destroy
-/// the game.
+/// this websocket will affect the game, and closing the connection will pause
+/// the game until the user rejoins or runs out of time.
 fn create_linked_websocket(
     user_id: usize,
     ws: warp::ws::WebSocket,
+    encoding: websocket::Encoding,
     state: &Arc<ServerState>,
 ) -> WebSocketHandler {
     let on_message = {
@@ -157,51 +980,568 @@ fn create_linked_websocket(
         let cloned_state = state.clone();
         move || user_disconnected(user_id, cloned_state.clone())
     };
-    WebSocketHandler::new(ws, user_id, on_message, on_disconnect)
+    WebSocketHandler::new(
+        ws,
+        websocket::Peer::User(user_id),
+        state.heartbeat,
+        state.overflow,
+        encoding,
+        on_message,
+        on_disconnect,
+    )
+}
+
+/// Create a websocket for someone watching a game. The game won't accept their
+/// moves, but they can still ask for the state of the table.
+fn create_spectator_websocket(
+    spectator: usize,
+    ws: warp::ws::WebSocket,
+    encoding: websocket::Encoding,
+    state: &Arc<ServerState>,
+) -> WebSocketHandler {
+    let on_message = {
+        let cloned_state = state.clone();
+        move |msg| handle_message(msg, spectator, cloned_state.clone())
+    };
+    let on_disconnect = {
+        let cloned_state = state.clone();
+        move || stop_watching(spectator, cloned_state.clone())
+    };
+    WebSocketHandler::new(
+        ws,
+        websocket::Peer::User(spectator),
+        state.heartbeat,
+        websocket::OverflowPolicy::Resync,
+        encoding,
+        on_message,
+        on_disconnect,
+    )
 }
 
 /// Use this for websockets that should not be connected to a game, and instead
 /// closed with a message.
-fn send_message_and_close(ws: warp::ws::WebSocket, message: OutputMessageType) {
-    let ws_handler = WebSocketHandler::new(ws, 0, async |_| {}, async || {});
+fn send_message_and_close(
+    ws: warp::ws::WebSocket,
+    encoding: websocket::Encoding,
+    message: OutputMessageType,
+) {
+    let ws_handler = WebSocketHandler::new(
+        ws,
+        websocket::Peer::TurnedAway,
+        websocket::Heartbeat::default(),
+        websocket::OverflowPolicy::Disconnect,
+        encoding,
+        async |_| {},
+        async || {},
+    );
     _ = ws_handler.send(message);
     ws_handler.close();
 }
 
-async fn send_message(message: OutputMessage, state: Arc<ServerState>) {
-    match state.users.pin().get(&message.recipient) {
-        None => return,
-        Some(websocket_handler) => _ = websocket_handler.send(message.message),
+/// Send a message, catching the recipient up with the state of the table if
+/// they've fallen behind. Returns whether they had to be caught up.
+async fn send_message(message: OutputMessage, state: Arc<ServerState>) -> bool {
+    let result = match state.users.pin().get(&message.recipient) {
+        Some(participant) => participant.send(message.message),
+        None => return false,
     };
+    if result != Err(websocket::SendError::Full) {
+        return false;
+    }
+    send_state(message.recipient, &state).await;
+    true
+}
+
+/// Send the user everything on the table, for when they've missed messages
+async fn send_state(user_id: usize, state: &Arc<ServerState>) {
+    let Ok((snapshot, score)) = state
+        .manager
+        .inspect(user_id, |game| (game.round().snapshot(), game.score()))
+        .await
+    else {
+        return;
+    };
+    if let Some(participant) = state.users.pin().get(&user_id) {
+        let updates = std::iter::once(game::OutputMessageType::GameState(snapshot))
+            .chain(score.map(game::OutputMessageType::MatchScore));
+        for update in updates {
+            _ = participant.send(OutputMessageType::GameUpdate(update));
+        }
+    }
+}
+
+async fn stop_watching(spectator: usize, state: Arc<ServerState>) {
+    state.users.pin().remove(&spectator);
+    state.manager.stop_watching(spectator).await;
 }
 
 async fn user_disconnected(user_id: usize, state: Arc<ServerState>) {
+    let disconnected_at = Instant::now();
+    state.users.pin().remove(&user_id);
+    let Ok(player) = state.manager.disconnect(user_id, disconnected_at).await else {
+        // This can happen if the user was never part of a game, or the game
+        // has already been destroyed
+        return;
+    };
+    let (Ok(players_in_game), Ok(spectators)) = (
+        state.manager.get_players(user_id).await,
+        state.manager.get_spectators(user_id).await,
+    ) else {
+        return;
+    };
+    let grace_period = state.reconnect_grace_period;
+    for recipient in players_in_game.into_iter().chain(spectators) {
+        let message = OutputMessageType::PlayerDisconnected {
+            player,
+            grace_period_ms: grace_period.as_millis().try_into().unwrap_or(u64::MAX),
+        };
+        send_message(OutputMessage { recipient, message }, state.clone()).await;
+    }
+
+    // Give them a chance to come back before giving up on the game
+    tokio::task::spawn(async move {
+        tokio::time::sleep(grace_period).await;
+        if state.manager.disconnected_since(user_id).await != Some(disconnected_at) {
+            // They came back (and maybe left again, in which case there's a
+            // newer timer running)
+            return;
+        }
+        destroy_game(user_id, state).await;
+    });
+}
+
+/// Play a game log saved by `destroy_game` back through the game logic
+fn replay_game_log(path: &Path) {
+    let log: game::log::GameLog = match std::fs::read_to_string(path)
+        .map_err(|e| e.to_string())
+        .and_then(|contents| serde_json::from_str(&contents).map_err(|e| e.to_string()))
+    {
+        Ok(log) => log,
+        Err(e) => {
+            println!("Could not read game log {}: {}", path.display(), e);
+            return;
+        }
+    };
+    match game::log::replay(&log) {
+        Ok(_) => println!("Replayed {} events, all matched", log.entries.len()),
+        Err(e) => println!(
+            "Replay diverged at event {}: {:?}\nexpected {:?}\ngot {:?}",
+            e.entry, log.entries[e.entry].event, e.expected, e.actual
+        ),
+    }
+}
+
+async fn destroy_game(user_id: usize, state: Arc<ServerState>) {
+    // Keep a record of the game for bug reports and post-game review
+    if let Ok((seed, Ok(log))) = state
+        .manager
+        .inspect(user_id, |game| {
+            let log = game.round().log();
+            (log.seed, serde_json::to_string(log))
+        })
+        .await
+        && let Ok(game_id) = state.manager.game_id(user_id).await
+    {
+        save_game_log(&state.game_log_dir, game_id, seed, log).await;
+    }
     let Ok(users_to_drop) = state.manager.destroy_users_game(user_id).await else {
-        // This can happen if the user was never part of a game
+        // Game has already been destroyed
         return;
     };
+    if !users_to_drop.is_empty() {
+        state.metrics.game_destroyed();
+    }
     let users_map = state.users.pin();
+    let names = state.names.pin();
     for user in users_to_drop.iter() {
+        names.remove(user);
         if let Some(websocket_output) = users_map.remove(user) {
+            _ = websocket_output.send(OutputMessageType::GameDestroyed);
             websocket_output.close();
         }
     }
 }
 
+/// Write a game's log to the log directory. Game IDs get reused, so the file
+/// is named after when the game ended as well, and an existing log is never
+/// overwritten.
+async fn save_game_log(dir: &Path, game_id: usize, seed: u64, log: String) {
+    let ended_at = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_millis())
+        .unwrap_or_default();
+    let path = dir.join(format!("{}-game{}-seed{}.json", ended_at, game_id, seed));
+    let saved = async {
+        tokio::fs::create_dir_all(dir).await?;
+        let mut file = tokio::fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&path)
+            .await?;
+        file.write_all(log.as_bytes()).await
+    };
+    match saved.await {
+        Ok(()) => println!("Saved game log to {}", path.display()),
+        Err(e) => println!("Could not save game log {}: {}", path.display(), e),
+    }
+}
+
 async fn handle_message(message: InputMessageType, sender: usize, state: Arc<ServerState>) {
+    let started = Instant::now();
+    let kind = message.kind();
+    respond_to_message(message, sender, state.clone()).await;
+    state.metrics.message_handled(kind, started.elapsed());
+}
+
+/// What to tell a client whose message the game couldn't take
+fn rejection(error: manager::HandleMessageError) -> message::ProtocolError {
+    match error {
+        manager::HandleMessageError::GameDoesNotExist => message::ProtocolError::GameNotFound,
+        manager::HandleMessageError::GamePaused => message::ProtocolError::GamePaused,
+        manager::HandleMessageError::NotAPlayer => message::ProtocolError::NotAPlayer,
+        manager::HandleMessageError::UnexpectedError => message::ProtocolError::ServerError,
+    }
+}
+
+async fn respond_to_message(message: InputMessageType, sender: usize, state: Arc<ServerState>) {
     match message {
         InputMessageType::GameUpdate(message) => {
-            let game_message = message::InputMessage { message, sender };
-            let Ok(game_responses) = state.manager.handle_message(game_message).await else {
-                return;
+            let received_at = Instant::now();
+            let round_trip = state
+                .users
+                .pin()
+                .get(&sender)
+                .and_then(|participant| participant.round_trip());
+            let game_message = message::InputMessage {
+                message,
+                sender,
+                received_at,
+                round_trip,
             };
-            let responses = game_responses.iter().map(|r| OutputMessage {
-                message: OutputMessageType::GameUpdate(r.message),
-                recipient: r.recipient,
-            });
-            for response in responses {
-                send_message(response, state.clone()).await;
+            let game_responses = match state.manager.handle_message(game_message).await {
+                Ok(game_responses) => game_responses,
+                Err(e) => {
+                    if let Some(participant) = state.users.pin().get(&sender) {
+                        participant.reject(rejection(e));
+                    }
+                    return;
+                }
+            };
+            send_game_responses(game_responses, state.clone()).await;
+            schedule_tick(sender, state);
+        }
+        InputMessageType::RequestState => send_state(sender, &state).await,
+    };
+}
+
+async fn send_game_responses(game_responses: Vec<game::OutputMessage>, state: Arc<ServerState>) {
+    // Everyone in the game hears who won, so any of them will do to find it
+    if let Some(response) = game_responses
+        .iter()
+        .find(|r| matches!(r.message, game::OutputMessageType::PlayerWins(_)))
+    {
+        record_finished_round(response.recipient, &state).await;
+    }
+    // Anyone caught up part way through already has the rest in their
+    // snapshot of the table
+    let mut caught_up = vec![];
+    for response in game_responses {
+        let recipient = response.recipient;
+        if caught_up.contains(&recipient) {
+            continue;
+        }
+        if let game::OutputMessageType::Rejected(code) = response.message {
+            if let Some(participant) = state.users.pin().get(&recipient) {
+                participant.reject(code);
             }
+            continue;
+        }
+        let response = OutputMessage {
+            message: OutputMessageType::GameUpdate(response.message),
+            recipient,
+        };
+        if send_message(response, state.clone()).await {
+            caught_up.push(recipient);
         }
+    }
+}
+
+/// Save the user's just finished round to the database, rating everyone that
+/// played it under a name. Rounds against bots aren't recorded, as beating
+/// them would be an easy way to climb the leaderboard.
+async fn record_finished_round(user_id: usize, state: &Arc<ServerState>) {
+    let Some(storage) = state.storage.clone() else {
+        return;
     };
+    let (Ok(players), Ok((Some(stats), seed))) = (
+        state.manager.get_players(user_id).await,
+        state
+            .manager
+            .inspect(user_id, |game| {
+                (game.round().round_stats(), game.round().seed())
+            })
+            .await,
+    ) else {
+        return;
+    };
+    let users = state.users.pin();
+    if players
+        .iter()
+        .any(|id| matches!(users.get(id), Some(Participant::Bot(_))))
+    {
+        return;
+    }
+    let names = state.names.pin();
+    let finished = storage::FinishedGame {
+        players: players.iter().map(|id| names.get(id).cloned()).collect(),
+        winner: stats.winner,
+        duration: stats.duration,
+        snaps: stats.snaps,
+        mistakes: stats.mistakes,
+        seed,
+    };
+    tokio::task::spawn_blocking(move || {
+        if let Err(e) = storage.record_game(&finished) {
+            println!("Could not record finished game: {}", e);
+        }
+    });
+}
+
+/// Open the database, carrying on without profiles if that's not possible
+fn open_storage(path: &Path) -> Option<Arc<storage::Storage>> {
+    match storage::Storage::open(path) {
+        Ok(storage) => Some(Arc::new(storage)),
+        Err(e) => {
+            println!(
+                "Could not open database {}, profiles are off: {}",
+                path.display(),
+                e
+            );
+            None
+        }
+    }
+}
+
+/// The name a player asked to play under, if any
+fn chosen_name(name: &Option<String>) -> Result<Option<String>, String> {
+    name.as_deref().map(storage::validate_name).transpose()
+}
+
+async fn leaderboard(state: Arc<ServerState>) -> warp::reply::Json {
+    let Some(storage) = state.storage.clone() else {
+        return warp::reply::json(&Vec::<storage::Profile>::new());
+    };
+    let top_players = tokio::task::spawn_blocking(move || storage.leaderboard(LEADERBOARD_SIZE))
+        .await
+        .map_err(|e| e.to_string())
+        .and_then(|result| result.map_err(|e| e.to_string()))
+        .unwrap_or_else(|e| {
+            println!("Could not read leaderboard: {}", e);
+            vec![]
+        });
+    warp::reply::json(&top_players)
+}
+
+async fn metrics(state: Arc<ServerState>) -> impl warp::Reply {
+    let (games_in_use, max_games) = state.manager.games_in_use().await;
+    let websockets = state
+        .users
+        .pin()
+        .values()
+        .filter(|participant| matches!(participant, Participant::Player(_)))
+        .count();
+    let text = state.metrics.render(&metrics::Gauges {
+        games_in_use,
+        max_games,
+        websockets,
+    });
+    warp::reply::with_header(text, "content-type", "text/plain; version=0.0.4")
+}
+
+/// Wait for the server to be told to stop, then wind it down: Turn away new
+/// players, warn everyone still connected, and give running games until the
+/// end of the drain period to finish before closing them.
+async fn shut_down(state: Arc<ServerState>) {
+    wait_for_stop_signal().await;
+    let drain = state.shutdown_drain;
+    println!("Shutting down, closing games in {}s", drain.as_secs());
+    _ = state.shutdown_at.set(Instant::now() + drain);
+    state.ready.store(false, Ordering::Relaxed);
+
+    if let Some(notice) = shutdown_notice(&state) {
+        for participant in state.users.pin().values() {
+            _ = participant.send(notice.clone());
+        }
+    }
+    for player in state
+        .quickplay
+        .take_waiting_for(Duration::ZERO, Instant::now())
+        .await
+    {
+        if let Some(notice) = shutdown_notice(&state) {
+            _ = player.ws_handler.send(notice);
+        }
+        player.ws_handler.close();
+    }
+
+    let deadline = Instant::now() + drain;
+    loop {
+        let (games_in_use, _) = state.manager.games_in_use().await;
+        let now = Instant::now();
+        if games_in_use == 0 || now >= deadline {
+            break;
+        }
+        tokio::time::sleep(SHUTDOWN_CHECK_INTERVAL.min(deadline - now)).await;
+    }
+    for participant in state.users.pin().values() {
+        participant.close();
+    }
+    tokio::time::sleep(SHUTDOWN_CLOSE_TIME).await;
+}
+
+/// Resolves on Ctrl-C, or SIGTERM where there is such a thing
+async fn wait_for_stop_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{SignalKind, signal};
+        if let Ok(mut terminate) = signal(SignalKind::terminate()) {
+            tokio::select! {
+                _ = tokio::signal::ctrl_c() => {}
+                _ = terminate.recv() => {}
+            }
+            return;
+        }
+    }
+    _ = tokio::signal::ctrl_c().await;
+}
+
+/// The protocol query of a websocket route, along with any encoding offered
+/// as a subprotocol
+fn protocol_query() -> impl Filter<Extract = (ProtocolQuery,), Error = warp::Rejection> + Clone {
+    warp::query::<ProtocolQuery>()
+        .and(warp::header::optional::<String>("sec-websocket-protocol"))
+        .map(|mut protocol: ProtocolQuery, offered: Option<String>| {
+            protocol.subprotocol = offered
+                .as_deref()
+                .and_then(websocket::Encoding::from_subprotocols);
+            protocol
+        })
+}
+
+/// Finish the websocket handshake, confirming the subprotocol if the client
+/// chose its encoding that way. `on_upgrade` gets the socket and the query
+/// back once the handshake succeeds.
+fn upgrade<F>(
+    ws: warp::ws::Ws,
+    protocol: ProtocolQuery,
+    on_upgrade: impl FnOnce(warp::ws::WebSocket, ProtocolQuery) -> F + Send + 'static,
+) -> warp::reply::Response
+where
+    F: Future<Output = ()> + Send + 'static,
+{
+    let subprotocol = protocol.accepted_subprotocol();
+    let mut response =
+        warp::Reply::into_response(ws.on_upgrade(move |socket| on_upgrade(socket, protocol)));
+    if let Some(encoding) = subprotocol {
+        response.headers_mut().insert(
+            "sec-websocket-protocol",
+            warp::http::HeaderValue::from_static(encoding.name()),
+        );
+    }
+    response
+}
+
+/// Why a new connection should be turned away, if it should: The client
+/// speaks the wrong protocol version, or the server is shutting down
+fn refusal(protocol: &ProtocolQuery, state: &ServerState) -> Option<OutputMessageType> {
+    if !message::version_supported(protocol.protocol) {
+        return Some(OutputMessageType::IncompatibleVersion {
+            protocol_version: message::PROTOCOL_VERSION,
+            min_protocol_version: message::MIN_PROTOCOL_VERSION,
+        });
+    }
+    shutdown_notice(state)
+}
+
+/// What to tell people once the server is shutting down
+fn shutdown_notice(state: &ServerState) -> Option<OutputMessageType> {
+    let closing_in = state
+        .shutdown_at
+        .get()?
+        .saturating_duration_since(Instant::now());
+    Some(OutputMessageType::ServerShuttingDown {
+        closing_in_ms: closing_in.as_millis().try_into().unwrap_or(u64::MAX),
+    })
+}
+
+/// Wake the user's game up when its next timer runs out. Keeps going for as
+/// long as the game has timers that do something.
+fn schedule_tick(user_id: usize, state: Arc<ServerState>) {
+    tokio::task::spawn(async move {
+        let Some(deadline) = state.manager.next_deadline(user_id).await else {
+            return;
+        };
+        tokio::time::sleep_until(deadline.into()).await;
+        let Ok(game_responses) = state.manager.tick(user_id, Instant::now()).await else {
+            return;
+        };
+        if game_responses.is_empty() {
+            // Another tick got there first
+            return;
+        }
+        send_game_responses(game_responses, state.clone()).await;
+        schedule_tick(user_id, state);
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Start a websocket handshake offering `subprotocols`, and return the
+    /// subprotocol the server confirmed, if any
+    async fn negotiated(query: &str, subprotocols: &str) -> Option<String> {
+        let route = warp::ws()
+            .and(protocol_query())
+            .map(|ws, protocol| upgrade(ws, protocol, |_, _| async {}));
+        let response = warp::test::request()
+            .path(&format!("/?{}", query))
+            .header("connection", "upgrade")
+            .header("upgrade", "websocket")
+            .header("sec-websocket-version", "13")
+            .header("sec-websocket-key", "dGhlIHNhbXBsZSBub25jZQ==")
+            .header("sec-websocket-protocol", subprotocols)
+            .reply(&route)
+            .await;
+        assert_eq!(
+            response.status(),
+            warp::http::StatusCode::SWITCHING_PROTOCOLS
+        );
+        response
+            .headers()
+            .get("sec-websocket-protocol")
+            .map(|value| value.to_str().unwrap().to_owned())
+    }
+
+    #[tokio::test]
+    async fn subprotocol_negotiated() {
+        assert_eq!(
+            negotiated("protocol=2", "graphql-ws, msgpack")
+                .await
+                .as_deref(),
+            Some("msgpack")
+        );
+        assert_eq!(negotiated("protocol=2", "graphql-ws").await, None);
+        // The query string wins, so the offer isn't taken up
+        assert_eq!(
+            negotiated("protocol=2&encoding=cbor", "msgpack").await,
+            None
+        );
+        assert_eq!(
+            negotiated("protocol=2&encoding=cbor", "cbor")
+                .await
+                .as_deref(),
+            Some("cbor")
+        );
+    }
 }
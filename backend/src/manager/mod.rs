@@ -1,38 +1,77 @@
 use papaya::HashMap;
+use rand::Rng;
 use std::sync::atomic::AtomicUsize;
-use tokio::sync::RwLock;
+use std::time::{Duration, Instant};
+use tokio::sync::{RwLock, RwLockMappedWriteGuard, RwLockWriteGuard};
 
 use crate::message;
 
 pub trait Game {
     type InputMessage;
-    type OutputMessage;
-    const NUM_PLAYERS: usize;
+    type OutputMessage: Clone;
+    /// Settings chosen when each game is created
+    type Config;
+
+    /// Number of seats at a game created with these settings
+    fn num_players(config: &Self::Config) -> usize;
+
+    fn new(config: Self::Config) -> Self;
 
     fn player_action(
         &mut self,
         message: message::InputMessage<usize, Self::InputMessage>,
     ) -> Vec<message::OutputMessage<usize, Self::OutputMessage>>;
+
+    /// Advance any timers that have run out by `now`
+    fn tick(&mut self, _now: Instant) -> Vec<message::OutputMessage<usize, Self::OutputMessage>> {
+        vec![]
+    }
+
+    /// The next time `tick` needs to be called, if any
+    fn next_deadline(&self) -> Option<Instant> {
+        None
+    }
+
+    /// The game is carrying on after being paused; push back any timers
+    fn resume(&mut self, _paused_for: Duration) {}
+
+    /// Whether spectators can see this message. Public messages must be sent
+    /// to every player, as spectators get a copy of the first player's.
+    fn is_public(_message: &Self::OutputMessage) -> bool {
+        false
+    }
 }
 
 type UserId = usize;
 type GameId = usize;
 
+/// Code players use to take a seat at a game. These are random, so nobody can
+/// guess their way into a stranger's game.
+pub type JoinCode = String;
+const JOIN_CODE_LENGTH: usize = 6;
+/// Letters and digits that are hard to mix up when reading a code out
+const JOIN_CODE_ALPHABET: &[u8] = b"ABCDEFGHJKLMNPQRSTUVWXYZ23456789";
+
 /// Manages game sessions: Essentially mapping user IDs to player numbers and
 /// creating/destroying games as needed in an async way.
-pub struct SessionManager<G: Game + Default> {
+pub struct SessionManager<G: Game> {
     games: Vec<RwLock<Option<GameContainer<G>>>>,
     freelist: RwLock<Vec<GameId>>,
     users: HashMap<UserId, GameRef>,
+    join_codes: HashMap<JoinCode, UserId>,
+    /// Codes for watching a game, pointing at its first player
+    watch_codes: HashMap<JoinCode, UserId>,
     id_counter: AtomicUsize,
 }
 
-impl<G: Game + Default> SessionManager<G> {
+impl<G: Game> SessionManager<G> {
     pub fn new(max_num_games: usize) -> Self {
         Self {
             games: Vec::from_iter((0..max_num_games).map(|_| RwLock::new(None))),
             freelist: RwLock::new((0..max_num_games).collect()),
             users: HashMap::default(),
+            join_codes: HashMap::default(),
+            watch_codes: HashMap::default(),
             id_counter: AtomicUsize::new(1),
         }
     }
@@ -43,15 +82,36 @@ impl<G: Game + Default> SessionManager<G> {
             .fetch_add(1, std::sync::atomic::Ordering::Relaxed)
     }
 
-    fn new_game(&self, users: Vec<UserId>) -> GameContainer<G> {
+    /// Create a join code nobody else is using, and point it at the user
+    fn new_join_code(&self, user: UserId) -> JoinCode {
+        new_code(&self.join_codes, user)
+    }
+
+    fn new_game(&self, users: Vec<UserId>, config: G::Config) -> GameContainer<G> {
         GameContainer {
-            game: G::default(),
+            game: G::new(config),
             id: self.new_id(),
+            seats: vec![Seat::Empty; users.len()],
+            join_codes: users.iter().map(|&user| self.new_join_code(user)).collect(),
+            watch_code: new_code(&self.watch_codes, users[0]),
             users,
+            spectators: vec![],
+            paused_since: None,
         }
     }
 
-    pub async fn create(&self) -> Result<NewGame, CreateGameError> {
+    /// Lock the user's game for writing, if it still exists
+    async fn lock_game(
+        &self,
+        user: UserId,
+    ) -> Option<RwLockMappedWriteGuard<'_, GameContainer<G>>> {
+        let &game_ref = self.users.pin().get(&user)?;
+        let game_slot = self.games[game_ref.index].write().await;
+        let game_container = RwLockWriteGuard::try_map(game_slot, |slot| slot.as_mut()).ok()?;
+        (game_container.id == game_ref.id).then_some(game_container)
+    }
+
+    pub async fn create(&self, config: G::Config) -> Result<NewGame, CreateGameError> {
         // Wait for lock on freelist
         let mut freelist = self.freelist.write().await;
         let Some(next_available_slot) = freelist.pop() else {
@@ -60,10 +120,12 @@ impl<G: Game + Default> SessionManager<G> {
         drop(freelist);
 
         // Generate new IDs for the players of this game
-        let users: Vec<UserId> = (0..G::NUM_PLAYERS).map(|_| self.new_id()).collect();
+        let users: Vec<UserId> = (0..G::num_players(&config))
+            .map(|_| self.new_id())
+            .collect();
 
         // Make a new game and create a reference to it
-        let new_game = self.new_game(users.clone());
+        let new_game = self.new_game(users.clone(), config);
         let game_ref = GameRef {
             index: next_available_slot,
             id: new_game.id,
@@ -89,47 +151,195 @@ impl<G: Game + Default> SessionManager<G> {
         &self,
         message: message::InputMessage<usize, G::InputMessage>,
     ) -> Result<Vec<message::OutputMessage<usize, G::OutputMessage>>, HandleMessageError> {
-        let Some(&game_ref) = self.users.pin().get(&message.sender).clone() else {
+        let Some(mut game_container) = self.lock_game(message.sender).await else {
             return Err(HandleMessageError::GameDoesNotExist);
         };
-        let mut game_slot = self.games[game_ref.index].write().await;
-        let Some(game_container) = game_slot.as_mut() else {
-            return Err(HandleMessageError::GameDoesNotExist);
-        };
-        if game_container.id != game_ref.id {
-            return Err(HandleMessageError::GameDoesNotExist);
-        }
 
         // Ok; game exists and we have a lock on the slot
-        let Some(sender_player_number) = game_container
-            .users
-            .iter()
-            .position(|i| *i == message.sender)
-        else {
-            return Err(HandleMessageError::UnexpectedError);
+        if game_container.paused_since.is_some() {
+            return Err(HandleMessageError::GamePaused);
+        }
+        let Some(sender_player_number) = game_container.player_number(message.sender) else {
+            // Spectators can't play
+            return Err(HandleMessageError::NotAPlayer);
         };
         let responses = game_container.game.player_action(message::InputMessage {
             sender: sender_player_number,
             message: message.message,
+            received_at: message.received_at,
+            round_trip: message.round_trip,
         });
-        let mapped_responses: Option<Vec<message::OutputMessage<usize, G::OutputMessage>>> =
-            responses
-                .into_iter()
-                .map(
-                    |message| match game_container.users.get(message.recipient) {
-                        Some(&user_id) => Some(message::OutputMessage {
-                            recipient: user_id,
-                            message: message.message,
-                        }),
-                        None => None,
-                    },
-                )
-                .collect();
-
-        match mapped_responses {
-            Some(responses) => Ok(responses),
-            None => Err(HandleMessageError::UnexpectedError),
+        game_container.map_to_users(responses)
+    }
+
+    /// Let the user's game handle any timers that have run out by `now`
+    pub async fn tick(
+        &self,
+        user: UserId,
+        now: Instant,
+    ) -> Result<Vec<message::OutputMessage<usize, G::OutputMessage>>, HandleMessageError> {
+        let Some(mut game_container) = self.lock_game(user).await else {
+            return Err(HandleMessageError::GameDoesNotExist);
+        };
+        if game_container.paused_since.is_some() {
+            return Err(HandleMessageError::GamePaused);
+        }
+        let responses = game_container.game.tick(now);
+        game_container.map_to_users(responses)
+    }
+
+    /// Record that the user has connected to their seat. If they're coming
+    /// back after losing their connection and nobody else is still missing,
+    /// the game carries on from where it was paused.
+    pub async fn connect(&self, user: UserId, now: Instant) -> Result<Connection, ConnectError> {
+        let Some(mut game_container) = self.lock_game(user).await else {
+            return Err(ConnectError::GameDoesNotExist);
+        };
+        let Some(player) = game_container.player_number(user) else {
+            return Err(ConnectError::GameDoesNotExist);
+        };
+        let rejoined = match game_container.seats[player] {
+            Seat::Connected => return Err(ConnectError::AlreadyConnected),
+            Seat::Empty => false,
+            Seat::Disconnected(_) => true,
+        };
+        game_container.seats[player] = Seat::Connected;
+
+        // The code they used to get here expires, and we give them a new one
+        // only they know in case they need to rejoin
+        let rejoin_code = self.new_join_code(user);
+        let old_code =
+            std::mem::replace(&mut game_container.join_codes[player], rejoin_code.clone());
+        self.join_codes.pin().remove(&old_code);
+
+        let everyone_back = game_container
+            .seats
+            .iter()
+            .all(|seat| !matches!(seat, Seat::Disconnected(_)));
+        let mut resumed = false;
+        if everyone_back && let Some(paused_since) = game_container.paused_since.take() {
+            game_container
+                .game
+                .resume(now.saturating_duration_since(paused_since));
+            resumed = true;
+        }
+        Ok(Connection {
+            player,
+            rejoin_code,
+            rejoined,
+            resumed,
+            seats_filled: game_container.seats.iter().all(|seat| *seat != Seat::Empty),
+        })
+    }
+
+    /// Record that the user has lost their connection, pausing their game
+    /// until they come back. Returns their player number.
+    pub async fn disconnect(&self, user: UserId, now: Instant) -> Result<usize, ()> {
+        let Some(mut game_container) = self.lock_game(user).await else {
+            return Err(());
+        };
+        let Some(player) = game_container.player_number(user) else {
+            return Err(());
+        };
+        game_container.seats[player] = Seat::Disconnected(now);
+        game_container.paused_since.get_or_insert(now);
+        Ok(player)
+    }
+
+    /// The user whose seat this code is for, if it's still valid
+    pub fn find_seat(&self, code: &str) -> Option<UserId> {
+        self.join_codes.pin().get(code).copied()
+    }
+
+    /// The current join code for each seat in the user's game. Once a seat has
+    /// been taken, only its player knows its code.
+    pub async fn join_codes(&self, user: UserId) -> Result<Vec<JoinCode>, ()> {
+        let Some(game_container) = self.lock_game(user).await else {
+            return Err(());
+        };
+        Ok(game_container.join_codes.clone())
+    }
+
+    /// Code for watching the user's game
+    pub async fn watch_code(&self, user: UserId) -> Result<JoinCode, ()> {
+        let Some(game_container) = self.lock_game(user).await else {
+            return Err(());
+        };
+        Ok(game_container.watch_code.clone())
+    }
+
+    /// ID of the user's game. IDs are reused once a game is destroyed
+    pub async fn game_id(&self, user: UserId) -> Result<usize, ()> {
+        let Some(game_container) = self.lock_game(user).await else {
+            return Err(());
+        };
+        Ok(game_container.id)
+    }
+
+    /// Start watching the game with this watch code. Returns the spectator's
+    /// ID, which gets the game's public messages from now on, along with `f`
+    /// run on the game as it is now.
+    pub async fn watch<R>(&self, code: &str, f: impl FnOnce(&G) -> R) -> Result<(UserId, R), ()> {
+        let Some(player) = self.watch_codes.pin().get(code).copied() else {
+            return Err(());
+        };
+        let Some(&game_ref) = self.users.pin().get(&player) else {
+            return Err(());
+        };
+        let Some(mut game_container) = self.lock_game(player).await else {
+            return Err(());
+        };
+        let spectator = self.new_id();
+        game_container.spectators.push(spectator);
+        self.users.pin().insert(spectator, game_ref);
+        Ok((spectator, f(&game_container.game)))
+    }
+
+    /// Stop sending the game's messages to the spectator
+    pub async fn stop_watching(&self, spectator: UserId) {
+        if let Some(mut game_container) = self.lock_game(spectator).await {
+            game_container.spectators.retain(|&id| id != spectator);
         }
+        self.users.pin().remove(&spectator);
+    }
+
+    /// When the user lost their connection, if they haven't come back since
+    pub async fn disconnected_since(&self, user: UserId) -> Option<Instant> {
+        let game_container = self.lock_game(user).await?;
+        match game_container.seats[game_container.player_number(user)?] {
+            Seat::Disconnected(since) => Some(since),
+            Seat::Empty | Seat::Connected => None,
+        }
+    }
+
+    /// When the user's game next needs a `tick`, if ever
+    pub async fn next_deadline(&self, user: UserId) -> Option<Instant> {
+        let &game_ref = self.users.pin().get(&user)?;
+        let game_slot = self.games[game_ref.index].read().await;
+        match game_slot.as_ref() {
+            Some(game_container) if game_container.id == game_ref.id => {
+                game_container.game.next_deadline()
+            }
+            _ => None,
+        }
+    }
+
+    /// Look at the state of the user's game without changing it
+    pub async fn inspect<R>(&self, user: UserId, f: impl FnOnce(&G) -> R) -> Result<R, ()> {
+        let Some(&game_ref) = self.users.pin().get(&user) else {
+            // User does not currently exist
+            return Err(());
+        };
+        match self.games[game_ref.index].read().await.as_ref() {
+            Some(game_container) if game_container.id == game_ref.id => Ok(f(&game_container.game)),
+            _ => Err(()),
+        }
+    }
+
+    /// Number of games being played, and the most there can be at once
+    pub async fn games_in_use(&self) -> (usize, usize) {
+        let free_slots = self.freelist.read().await.len();
+        (self.games.len() - free_slots, self.games.len())
     }
 
     /// Get a IDs of players in the same game
@@ -144,7 +354,15 @@ impl<G: Game + Default> SessionManager<G> {
         }
     }
 
-    /// Destroy a game and return vec of users to notify
+    /// Get IDs of spectators watching the user's game
+    pub async fn get_spectators(&self, user: UserId) -> Result<Vec<UserId>, ()> {
+        let Some(game_container) = self.lock_game(user).await else {
+            return Err(());
+        };
+        Ok(game_container.spectators.clone())
+    }
+
+    /// Destroy a game and return vec of users (and spectators) to notify
     pub async fn destroy_users_game(&self, user: UserId) -> Result<Vec<UserId>, DestroyGameError> {
         let Some(&game_ref) = self.users.pin().get(&user) else {
             // User does not currently exist
@@ -164,18 +382,29 @@ impl<G: Game + Default> SessionManager<G> {
             return Ok(vec![]);
         }
 
-        // Remove users from hashmap
+        // Remove users and their join codes from the hashmaps
+        let to_notify: Vec<UserId> = game_container
+            .users
+            .iter()
+            .chain(game_container.spectators.iter())
+            .copied()
+            .collect();
         {
             let users_map = self.users.pin();
-            for user in game_container.users.iter() {
+            for user in to_notify.iter() {
                 users_map.remove(user);
             }
+            let join_codes = self.join_codes.pin();
+            for code in game_container.join_codes.iter() {
+                join_codes.remove(code);
+            }
+            self.watch_codes.pin().remove(&game_container.watch_code);
         }
 
         // Add the slot to the freelist
         self.freelist.write().await.push(game_ref.index);
 
-        Ok(game_container.users)
+        Ok(to_notify)
     }
 }
 
@@ -191,9 +420,40 @@ pub enum DestroyGameError {
 // Handling player actions
 pub enum HandleMessageError {
     GameDoesNotExist,
+    GamePaused,
+    /// Sender is only watching the game
+    NotAPlayer,
     UnexpectedError,
 }
 
+// Players connecting to their seats
+pub enum ConnectError {
+    GameDoesNotExist,
+    AlreadyConnected,
+}
+
+pub struct Connection {
+    pub player: usize,
+    /// Code the player can use to rejoin, should they lose their connection
+    pub rejoin_code: JoinCode,
+    /// Player lost their connection earlier and has come back
+    pub rejoined: bool,
+    /// Game was paused and is now carrying on
+    pub resumed: bool,
+    /// Every seat at the game has had a player connect to it, even if some of
+    /// them have lost their connection since
+    pub seats_filled: bool,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Seat {
+    /// Nobody has connected to this seat yet
+    Empty,
+    Connected,
+    /// Player lost their connection at this time, and may come back
+    Disconnected(Instant),
+}
+
 /// Reference to a game. Since we store all games in a Vec, the reference is the
 /// index of that game in the Vec, plus the game's ID (in case the space has
 /// been re-used by another game).
@@ -207,36 +467,106 @@ struct GameContainer<G: Game> {
     game: G,
     id: GameId,
     users: Vec<UserId>,
+    seats: Vec<Seat>,
+    /// Current code for each seat
+    join_codes: Vec<JoinCode>,
+    watch_code: JoinCode,
+    spectators: Vec<UserId>,
+    /// When the game was paused for a missing player, if it currently is
+    paused_since: Option<Instant>,
+}
+
+impl<G: Game> GameContainer<G> {
+    fn player_number(&self, user: UserId) -> Option<usize> {
+        self.users.iter().position(|i| *i == user)
+    }
+
+    /// Swap the player numbers in the game's messages for user IDs, copying
+    /// public messages to spectators
+    fn map_to_users(
+        &self,
+        responses: Vec<message::OutputMessage<usize, G::OutputMessage>>,
+    ) -> Result<Vec<message::OutputMessage<usize, G::OutputMessage>>, HandleMessageError> {
+        let mut mapped_responses = Vec::with_capacity(responses.len());
+        for message in responses {
+            let Some(&user_id) = self.users.get(message.recipient) else {
+                return Err(HandleMessageError::UnexpectedError);
+            };
+            if message.recipient == 0 && G::is_public(&message.message) {
+                mapped_responses.extend(self.spectators.iter().map(|&spectator| {
+                    message::OutputMessage {
+                        recipient: spectator,
+                        message: message.message.clone(),
+                    }
+                }));
+            }
+            mapped_responses.push(message::OutputMessage {
+                recipient: user_id,
+                message: message.message,
+            });
+        }
+        Ok(mapped_responses)
+    }
+}
+
+/// Create a code nobody else is using in `codes`, and point it at the user
+fn new_code(codes: &HashMap<JoinCode, UserId>, user: UserId) -> JoinCode {
+    let codes = codes.pin();
+    let mut rng = rand::rng();
+    loop {
+        let code: JoinCode = (0..JOIN_CODE_LENGTH)
+            .map(|_| JOIN_CODE_ALPHABET[rng.random_range(0..JOIN_CODE_ALPHABET.len())] as char)
+            .collect();
+        if codes.try_insert(code.clone(), user).is_ok() {
+            return code;
+        }
+    }
 }
 
 // TESTS
 
-#[derive(Default)]
-struct DummyGame {}
+#[cfg(test)]
+struct DummyGame {
+    num_players: usize,
+}
 
+#[cfg(test)]
 enum DummyInputMessage {
     UserSays(usize),
 }
+#[cfg(test)]
+#[derive(Clone)]
+#[allow(dead_code)] // Tests only look at what was said
 enum DummyOutputMessage {
     OtherUserSays(usize, usize),
 }
 
+#[cfg(test)]
 impl Game for DummyGame {
     type InputMessage = DummyInputMessage;
     type OutputMessage = DummyOutputMessage;
-    const NUM_PLAYERS: usize = 3;
+    type Config = usize;
+    fn num_players(config: &usize) -> usize {
+        *config
+    }
+    fn new(num_players: usize) -> Self {
+        DummyGame { num_players }
+    }
     fn player_action(
         &mut self,
         message: message::InputMessage<usize, Self::InputMessage>,
     ) -> Vec<message::OutputMessage<usize, Self::OutputMessage>> {
         let DummyInputMessage::UserSays(num) = message.message;
-        (0..Self::NUM_PLAYERS)
+        (0..self.num_players)
             .map(|i| message::OutputMessage {
                 recipient: i,
                 message: DummyOutputMessage::OtherUserSays(message.sender, num),
             })
             .collect()
     }
+    fn is_public(_message: &DummyOutputMessage) -> bool {
+        true
+    }
 }
 
 #[cfg(test)]
@@ -246,11 +576,11 @@ mod tests {
     #[tokio::test]
     async fn game_creation_ids_unique() {
         let manager: SessionManager<DummyGame> = SessionManager::new(5);
-        let Ok(users_1) = manager.create().await else {
+        let Ok(users_1) = manager.create(3).await else {
             panic!()
         };
         assert_eq!(users_1.len(), 3);
-        let Ok(users_2) = manager.create().await else {
+        let Ok(users_2) = manager.create(3).await else {
             panic!()
         };
         assert_eq!(users_2.len(), 3);
@@ -258,13 +588,42 @@ mod tests {
         assert!(users_1.iter().all(|id| !users_2.contains(id)));
     }
 
+    #[tokio::test]
+    async fn games_in_use_counts_slots() {
+        let manager: SessionManager<DummyGame> = SessionManager::new(5);
+        assert_eq!(manager.games_in_use().await, (0, 5));
+        let Ok(users_1) = manager.create(3).await else {
+            panic!()
+        };
+        let Ok(_) = manager.create(3).await else {
+            panic!()
+        };
+        assert_eq!(manager.games_in_use().await, (2, 5));
+
+        assert!(manager.destroy_users_game(users_1[0]).await.is_ok());
+        assert_eq!(manager.games_in_use().await, (1, 5));
+    }
+
+    #[tokio::test]
+    async fn player_count_chosen_per_game() {
+        let manager: SessionManager<DummyGame> = SessionManager::new(5);
+        let Ok(users_1) = manager.create(2).await else {
+            panic!()
+        };
+        assert_eq!(users_1.len(), 2);
+        let Ok(users_2) = manager.create(5).await else {
+            panic!()
+        };
+        assert_eq!(users_2.len(), 5);
+    }
+
     #[tokio::test]
     async fn game_message_mapping() {
         let manager: SessionManager<DummyGame> = SessionManager::new(5);
         for _ in 0..2 {
-            _ = manager.create().await;
+            _ = manager.create(3).await;
         }
-        let Ok(users) = manager.create().await else {
+        let Ok(users) = manager.create(3).await else {
             panic!()
         };
 
@@ -272,6 +631,8 @@ mod tests {
             let msg = message::InputMessage {
                 sender: *sender,
                 message: DummyInputMessage::UserSays(99),
+                received_at: Instant::now(),
+                round_trip: None,
             };
             let Ok(responses) = manager.handle_message(msg).await else {
                 panic!()
@@ -288,11 +649,11 @@ mod tests {
     async fn max_games_enforced() {
         let manager: SessionManager<DummyGame> = SessionManager::new(5);
         for _ in 0..5 {
-            let Ok(_) = manager.create().await else {
+            let Ok(_) = manager.create(3).await else {
                 panic!()
             };
         }
-        let Err(CreateGameError::ServerFull) = manager.create().await else {
+        let Err(CreateGameError::ServerFull) = manager.create(3).await else {
             panic!()
         };
     }
@@ -301,22 +662,150 @@ mod tests {
     async fn game_cleanup_frees_up_slots() {
         let manager: SessionManager<DummyGame> = SessionManager::new(5);
         for _ in 0..4 {
-            let Ok(_) = manager.create().await else {
+            let Ok(_) = manager.create(3).await else {
+                panic!()
+            };
+        }
+        let Ok(users) = manager.create(3).await else {
+            panic!()
+        };
+        let Err(CreateGameError::ServerFull) = manager.create(3).await else {
+            panic!()
+        };
+
+        let Ok(_) = manager.destroy_users_game(users[0]).await else {
+            panic!()
+        };
+        let Ok(_) = manager.create(3).await else {
+            panic!()
+        };
+    }
+
+    #[tokio::test]
+    async fn disconnect_pauses_until_rejoin() {
+        let manager: SessionManager<DummyGame> = SessionManager::new(5);
+        let Ok(users) = manager.create(2).await else {
+            panic!()
+        };
+        let now = Instant::now();
+        for user in users.iter() {
+            let Ok(connection) = manager.connect(*user, now).await else {
                 panic!()
             };
+            assert!(!connection.rejoined);
         }
-        let Ok(users) = manager.create().await else {
+        let Err(ConnectError::AlreadyConnected) = manager.connect(users[0], now).await else {
+            panic!()
+        };
+
+        let Ok(1) = manager.disconnect(users[1], now).await else {
+            panic!()
+        };
+        assert_eq!(manager.disconnected_since(users[1]).await, Some(now));
+        let msg = message::InputMessage {
+            sender: users[0],
+            message: DummyInputMessage::UserSays(1),
+            received_at: now,
+            round_trip: None,
+        };
+        let Err(HandleMessageError::GamePaused) = manager.handle_message(msg).await else {
             panic!()
         };
-        let Err(CreateGameError::ServerFull) = manager.create().await else {
+
+        let Ok(connection) = manager.connect(users[1], now).await else {
             panic!()
         };
+        assert!(connection.rejoined && connection.resumed && connection.seats_filled);
+        assert_eq!(manager.disconnected_since(users[1]).await, None);
+        let msg = message::InputMessage {
+            sender: users[0],
+            message: DummyInputMessage::UserSays(1),
+            received_at: now,
+            round_trip: None,
+        };
+        let Ok(_) = manager.handle_message(msg).await else {
+            panic!()
+        };
+    }
+
+    #[tokio::test]
+    async fn join_codes_expire_when_seat_taken() {
+        let manager: SessionManager<DummyGame> = SessionManager::new(5);
+        let Ok(users) = manager.create(3).await else {
+            panic!()
+        };
+        let Ok(codes) = manager.join_codes(users[0]).await else {
+            panic!()
+        };
+        assert_eq!(codes.len(), 3);
+        for (code, user) in codes.iter().zip(users.iter()) {
+            assert_eq!(code.len(), JOIN_CODE_LENGTH);
+            assert!(code.bytes().all(|c| JOIN_CODE_ALPHABET.contains(&c)));
+            assert_eq!(manager.find_seat(code), Some(*user));
+        }
+
+        let Ok(connection) = manager.connect(users[1], Instant::now()).await else {
+            panic!()
+        };
+        assert_eq!(manager.find_seat(&codes[1]), None);
+        assert_ne!(connection.rejoin_code, codes[1]);
+        assert_eq!(manager.find_seat(&connection.rejoin_code), Some(users[1]));
 
         let Ok(_) = manager.destroy_users_game(users[0]).await else {
             panic!()
         };
-        let Ok(_) = manager.create().await else {
+        assert_eq!(manager.find_seat(&codes[0]), None);
+        assert_eq!(manager.find_seat(&connection.rejoin_code), None);
+    }
+
+    #[tokio::test]
+    async fn spectators_watch_without_playing() {
+        let manager: SessionManager<DummyGame> = SessionManager::new(5);
+        let Ok(users) = manager.create(2).await else {
+            panic!()
+        };
+        let Ok(code) = manager.watch_code(users[1]).await else {
+            panic!()
+        };
+        let Ok((spectator, num_players)) = manager.watch(&code, |game| game.num_players).await
+        else {
+            panic!()
+        };
+        assert_eq!(num_players, 2);
+        assert_eq!(manager.get_spectators(users[0]).await, Ok(vec![spectator]));
+
+        let msg = message::InputMessage {
+            sender: users[0],
+            message: DummyInputMessage::UserSays(1),
+            received_at: Instant::now(),
+            round_trip: None,
+        };
+        let Ok(responses) = manager.handle_message(msg).await else {
+            panic!()
+        };
+        let recipients: Vec<UserId> = responses.iter().map(|r| r.recipient).collect();
+        assert_eq!(recipients, vec![spectator, users[0], users[1]]);
+
+        let msg = message::InputMessage {
+            sender: spectator,
+            message: DummyInputMessage::UserSays(1),
+            received_at: Instant::now(),
+            round_trip: None,
+        };
+        let Err(HandleMessageError::NotAPlayer) = manager.handle_message(msg).await else {
+            panic!()
+        };
+
+        manager.stop_watching(spectator).await;
+        assert_eq!(manager.get_spectators(users[0]).await, Ok(vec![]));
+        let Ok((spectator, _)) = manager.watch(&code, |_| ()).await else {
+            panic!()
+        };
+
+        let Ok(to_notify) = manager.destroy_users_game(users[0]).await else {
             panic!()
         };
+        assert!(to_notify.contains(&spectator));
+        assert!(manager.watch(&code, |_| ()).await.is_err());
     }
 }
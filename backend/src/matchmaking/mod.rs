@@ -0,0 +1,155 @@
+use std::collections::VecDeque;
+use std::sync::atomic::AtomicUsize;
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
+
+/// Identifies a place in the queue
+pub type Ticket = usize;
+
+/// Players waiting to be matched up with strangers, first come first served
+pub struct MatchQueue<T> {
+    waiting: Mutex<VecDeque<Waiter<T>>>,
+    ticket_counter: AtomicUsize,
+}
+
+struct Waiter<T> {
+    ticket: Ticket,
+    joined_at: Instant,
+    player: T,
+}
+
+impl<T> MatchQueue<T> {
+    pub fn new() -> Self {
+        Self {
+            waiting: Mutex::new(VecDeque::new()),
+            ticket_counter: AtomicUsize::new(1),
+        }
+    }
+
+    /// Get a ticket to use when joining the queue, e.g. so a player can be
+    /// taken out of the queue before they've joined it
+    pub fn new_ticket(&self) -> Ticket {
+        self.ticket_counter
+            .fetch_add(1, std::sync::atomic::Ordering::Relaxed)
+    }
+
+    pub async fn join(&self, ticket: Ticket, player: T, now: Instant) {
+        self.waiting.lock().await.push_back(Waiter {
+            ticket,
+            joined_at: now,
+            player,
+        });
+    }
+
+    /// Take the player out of the queue, if they're still in it
+    pub async fn leave(&self, ticket: Ticket) -> Option<T> {
+        let mut waiting = self.waiting.lock().await;
+        let index = waiting.iter().position(|w| w.ticket == ticket)?;
+        waiting.remove(index).map(|w| w.player)
+    }
+
+    /// Take the `num_players` players that have waited longest, if there are
+    /// enough of them
+    pub async fn take_match(&self, num_players: usize) -> Option<Vec<T>> {
+        let mut waiting = self.waiting.lock().await;
+        if waiting.len() < num_players {
+            return None;
+        }
+        Some(waiting.drain(..num_players).map(|w| w.player).collect())
+    }
+
+    /// Put players taken out of the queue back at the front of it, keeping
+    /// their tickets and the order they're given in
+    pub async fn put_back(&self, players: Vec<(Ticket, Instant, T)>) {
+        let mut waiting = self.waiting.lock().await;
+        for (ticket, joined_at, player) in players.into_iter().rev() {
+            waiting.push_front(Waiter {
+                ticket,
+                joined_at,
+                player,
+            });
+        }
+    }
+
+    /// Take everyone that has been waiting for at least `wait` by `now`
+    pub async fn take_waiting_for(&self, wait: Duration, now: Instant) -> Vec<T> {
+        let mut waiting = self.waiting.lock().await;
+        // The queue is in the order people joined, so the longest waiters are
+        // at the front
+        let num_waited = waiting
+            .iter()
+            .take_while(|w| now.saturating_duration_since(w.joined_at) >= wait)
+            .count();
+        waiting.drain(..num_waited).map(|w| w.player).collect()
+    }
+
+    /// Run `f` on each waiting player along with their place in the queue,
+    /// counting from 1
+    pub async fn for_each_position(&self, mut f: impl FnMut(&T, usize)) {
+        let waiting = self.waiting.lock().await;
+        for (i, waiter) in waiting.iter().enumerate() {
+            f(&waiter.player, i + 1);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn queue_of(players: &[&'static str], now: Instant) -> MatchQueue<&'static str> {
+        let queue = MatchQueue::new();
+        for (i, &player) in players.iter().enumerate() {
+            let ticket = queue.new_ticket();
+            queue
+                .join(ticket, player, now + Duration::from_secs(i as u64))
+                .await;
+        }
+        queue
+    }
+
+    #[tokio::test]
+    async fn matches_longest_waiting_first() {
+        let queue = queue_of(&["a", "b", "c"], Instant::now()).await;
+        assert_eq!(queue.take_match(2).await, Some(vec!["a", "b"]));
+        assert_eq!(queue.take_match(2).await, None);
+
+        let mut positions = vec![];
+        queue
+            .for_each_position(|&player, position| positions.push((player, position)))
+            .await;
+        assert_eq!(positions, vec![("c", 1)]);
+    }
+
+    #[tokio::test]
+    async fn leaving_the_queue() {
+        let queue = MatchQueue::new();
+        let ticket = queue.new_ticket();
+        queue.join(ticket, "a", Instant::now()).await;
+        assert_eq!(queue.leave(ticket).await, Some("a"));
+        assert_eq!(queue.leave(ticket).await, None);
+        assert_eq!(queue.take_match(1).await, None);
+    }
+
+    #[tokio::test]
+    async fn put_back_at_the_front() {
+        let now = Instant::now();
+        let queue = queue_of(&["a", "b", "c"], now).await;
+        assert_eq!(queue.take_match(2).await, Some(vec!["a", "b"]));
+        queue.put_back(vec![(1, now, "a"), (2, now, "b")]).await;
+        assert_eq!(queue.leave(2).await, Some("b"));
+        assert_eq!(queue.take_match(2).await, Some(vec!["a", "c"]));
+    }
+
+    #[tokio::test]
+    async fn take_long_waiters() {
+        let now = Instant::now();
+        let queue = queue_of(&["a", "b", "c"], now).await;
+        let later = now + Duration::from_secs(11);
+        assert_eq!(
+            queue.take_waiting_for(Duration::from_secs(10), later).await,
+            vec!["a", "b"]
+        );
+        assert_eq!(queue.take_match(1).await, Some(vec!["c"]));
+    }
+}
@@ -1,9 +1,101 @@
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+
+/// Version of the messages the server sends and understands. Bump it whenever
+/// their shape changes in a way older clients can't cope with.
+pub const PROTOCOL_VERSION: u32 = 2;
+
+/// Oldest client protocol version the server still understands
+pub const MIN_PROTOCOL_VERSION: u32 = 2;
+
+/// What clients from before versioning speak. They don't say which version
+/// they speak, and expect messages the server no longer sends.
+const LEGACY_PROTOCOL_VERSION: u32 = 1;
+
+/// Whether the server can talk to a client speaking `version`
+pub fn version_supported(version: Option<u32>) -> bool {
+    (MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION).contains(&version.unwrap_or(LEGACY_PROTOCOL_VERSION))
+}
+
 pub struct InputMessage<UserId, Message> {
     pub sender: UserId,
     pub message: Message,
+    /// When the server received this message
+    pub received_at: Instant,
+    /// Round-trip time of the sender's connection, if it has been measured
+    pub round_trip: Option<Duration>,
 }
 
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
 pub struct OutputMessage<UserId, Message> {
     pub recipient: UserId,
     pub message: Message,
 }
+
+/// Why a message from a client was turned down. Sent back to the client, so
+/// it knows its action was ignored.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub enum ProtocolError {
+    /// Couldn't be decoded at all: not valid in whichever encoding the
+    /// connection uses
+    Malformed,
+    /// Decoded fine, but not a message the server knows
+    UnknownVariant,
+    /// Only the player whose turn it is can do that
+    NotYourTurn,
+    /// The round is over; the only thing left to do is play again
+    GameEnded,
+    /// Already responded to this card
+    DuplicateResponse,
+    /// Nothing can be played until everyone's back
+    GamePaused,
+    /// Spectators can't play
+    NotAPlayer,
+    /// The game has been closed, or the sender was never in one
+    GameNotFound,
+    /// Something went wrong on the server's side
+    ServerError,
+}
+
+impl ProtocolError {
+    /// The server couldn't make sense of the message at all, as opposed to
+    /// it breaking the rules of the game. Players mashing buttons make plenty
+    /// of the latter, but a client sending the former is broken.
+    pub fn is_unreadable(self) -> bool {
+        matches!(
+            self,
+            ProtocolError::Malformed | ProtocolError::UnknownVariant
+        )
+    }
+}
+
+impl fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ProtocolError::Malformed => write!(f, "malformed message"),
+            ProtocolError::UnknownVariant => write!(f, "unknown message"),
+            ProtocolError::NotYourTurn => write!(f, "not your turn"),
+            ProtocolError::GameEnded => write!(f, "game has ended"),
+            ProtocolError::DuplicateResponse => write!(f, "already responded"),
+            ProtocolError::GamePaused => write!(f, "game is paused"),
+            ProtocolError::NotAPlayer => write!(f, "not a player"),
+            ProtocolError::GameNotFound => write!(f, "no such game"),
+            ProtocolError::ServerError => write!(f, "server error"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn versions_checked() {
+        assert!(!version_supported(None));
+        assert!(version_supported(Some(PROTOCOL_VERSION)));
+        assert!(!version_supported(Some(MIN_PROTOCOL_VERSION - 1)));
+        assert!(!version_supported(Some(PROTOCOL_VERSION + 1)));
+    }
+}
@@ -0,0 +1,194 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+/// Upper bounds of the latency histogram's buckets, in seconds
+const LATENCY_BUCKETS: [f64; 10] = [
+    0.0001, 0.00025, 0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1,
+];
+
+/// Counts of what the server has been doing, for Prometheus to scrape
+#[derive(Default)]
+pub struct Metrics {
+    games_created: AtomicU64,
+    games_destroyed: AtomicU64,
+    server_full: AtomicU64,
+    /// How long messages took to handle, by message type
+    messages: Mutex<BTreeMap<&'static str, Histogram>>,
+}
+
+/// The state of things at the moment the metrics are read
+pub struct Gauges {
+    pub games_in_use: usize,
+    pub max_games: usize,
+    pub websockets: usize,
+}
+
+#[derive(Default)]
+struct Histogram {
+    /// Observations no bigger than each of `LATENCY_BUCKETS`
+    buckets: [u64; LATENCY_BUCKETS.len()],
+    count: u64,
+    sum: f64,
+}
+
+impl Histogram {
+    fn observe(&mut self, value: f64) {
+        for (bucket, &bound) in self.buckets.iter_mut().zip(&LATENCY_BUCKETS) {
+            if value <= bound {
+                *bucket += 1;
+            }
+        }
+        self.count += 1;
+        self.sum += value;
+    }
+}
+
+impl Metrics {
+    pub fn game_created(&self) {
+        self.games_created.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn game_destroyed(&self) {
+        self.games_destroyed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn server_full(&self) {
+        self.server_full.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Note that a message of type `kind` took `elapsed` to handle
+    pub fn message_handled(&self, kind: &'static str, elapsed: Duration) {
+        let mut messages = self.messages.lock().unwrap_or_else(|e| e.into_inner());
+        messages
+            .entry(kind)
+            .or_default()
+            .observe(elapsed.as_secs_f64());
+    }
+
+    /// Everything in the Prometheus text format
+    pub fn render(&self, gauges: &Gauges) -> String {
+        let mut out = String::new();
+        let mut metric = |name: &str, kind: &str, help: &str, value: String| {
+            _ = writeln!(out, "# HELP {} {}", name, help);
+            _ = writeln!(out, "# TYPE {} {}", name, kind);
+            _ = writeln!(out, "{} {}", name, value);
+        };
+        metric(
+            "snap_games_active",
+            "gauge",
+            "Games being played",
+            gauges.games_in_use.to_string(),
+        );
+        metric(
+            "snap_games_max",
+            "gauge",
+            "Most games that can be played at once",
+            gauges.max_games.to_string(),
+        );
+        metric(
+            "snap_websockets_connected",
+            "gauge",
+            "Websockets connected to a game, including spectators",
+            gauges.websockets.to_string(),
+        );
+        let counters = [
+            (
+                "snap_games_created_total",
+                "Games created",
+                &self.games_created,
+            ),
+            (
+                "snap_games_destroyed_total",
+                "Games destroyed",
+                &self.games_destroyed,
+            ),
+            (
+                "snap_server_full_total",
+                "Games turned away because the server was full",
+                &self.server_full,
+            ),
+        ];
+        for (name, help, counter) in counters {
+            metric(
+                name,
+                "counter",
+                help,
+                counter.load(Ordering::Relaxed).to_string(),
+            );
+        }
+
+        let messages = self.messages.lock().unwrap_or_else(|e| e.into_inner());
+        _ = writeln!(
+            out,
+            "# HELP snap_messages_handled_total Messages handled from players"
+        );
+        _ = writeln!(out, "# TYPE snap_messages_handled_total counter");
+        for (kind, histogram) in messages.iter() {
+            _ = writeln!(
+                out,
+                "snap_messages_handled_total{{type=\"{}\"}} {}",
+                kind, histogram.count
+            );
+        }
+        let name = "snap_handle_message_duration_seconds";
+        _ = writeln!(out, "# HELP {} Time taken to handle a message", name);
+        _ = writeln!(out, "# TYPE {} histogram", name);
+        for (kind, histogram) in messages.iter() {
+            for (bound, count) in LATENCY_BUCKETS.iter().zip(histogram.buckets) {
+                _ = writeln!(
+                    out,
+                    "{}_bucket{{type=\"{}\",le=\"{}\"}} {}",
+                    name, kind, bound, count
+                );
+            }
+            _ = writeln!(
+                out,
+                "{}_bucket{{type=\"{}\",le=\"+Inf\"}} {}",
+                name, kind, histogram.count
+            );
+            _ = writeln!(out, "{}_sum{{type=\"{}\"}} {}", name, kind, histogram.sum);
+            _ = writeln!(
+                out,
+                "{}_count{{type=\"{}\"}} {}",
+                name, kind, histogram.count
+            );
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renders_prometheus_text() {
+        let metrics = Metrics::default();
+        metrics.game_created();
+        metrics.game_created();
+        metrics.message_handled("Draw", Duration::from_micros(300));
+        metrics.message_handled("Draw", Duration::from_secs(1));
+        let text = metrics.render(&Gauges {
+            games_in_use: 2,
+            max_games: 10,
+            websockets: 3,
+        });
+
+        let lines: Vec<&str> = text.lines().collect();
+        for expected in [
+            "snap_games_active 2",
+            "snap_games_created_total 2",
+            "snap_server_full_total 0",
+            "snap_messages_handled_total{type=\"Draw\"} 2",
+            "snap_handle_message_duration_seconds_bucket{type=\"Draw\",le=\"0.00025\"} 0",
+            "snap_handle_message_duration_seconds_bucket{type=\"Draw\",le=\"0.0005\"} 1",
+            "snap_handle_message_duration_seconds_bucket{type=\"Draw\",le=\"+Inf\"} 2",
+            "snap_handle_message_duration_seconds_count{type=\"Draw\"} 2",
+        ] {
+            assert!(lines.contains(&expected), "Missing {}", expected);
+        }
+    }
+}
@@ -0,0 +1,277 @@
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use rusqlite::{Connection, params};
+use serde::{Deserialize, Serialize};
+
+/// Rating given to players the first time they finish a game
+pub const STARTING_RATING: f64 = 1200.0;
+
+/// Most a rating can move in a two player game. With more players, each loss
+/// counts for less.
+const RATING_K_FACTOR: f64 = 32.0;
+
+pub const MAX_NAME_LENGTH: usize = 20;
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS players (
+        name TEXT PRIMARY KEY,
+        rating REAL NOT NULL,
+        games_played INTEGER NOT NULL DEFAULT 0,
+        wins INTEGER NOT NULL DEFAULT 0
+    );
+    CREATE TABLE IF NOT EXISTS games (
+        id INTEGER PRIMARY KEY,
+        finished_at INTEGER NOT NULL,
+        duration_ms INTEGER NOT NULL,
+        snaps INTEGER NOT NULL,
+        mistakes INTEGER NOT NULL,
+        seed TEXT NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS game_players (
+        game_id INTEGER NOT NULL REFERENCES games(id),
+        seat INTEGER NOT NULL,
+        name TEXT REFERENCES players(name),
+        won INTEGER NOT NULL,
+        PRIMARY KEY (game_id, seat)
+    );
+";
+
+/// A player's record, as shown on the leaderboard
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct Profile {
+    pub name: String,
+    pub rating: f64,
+    pub games_played: u32,
+    pub wins: u32,
+}
+
+/// A game that has just been won, ready to be recorded
+#[derive(Clone, Debug)]
+pub struct FinishedGame {
+    /// Name of the player in each seat, or `None` for anonymous players
+    pub players: Vec<Option<String>>,
+    /// Seat of the player that won
+    pub winner: usize,
+    pub duration: Duration,
+    pub snaps: usize,
+    pub mistakes: usize,
+    pub seed: u64,
+}
+
+/// Player profiles and finished games, kept in a SQLite database. Profiles are
+/// keyed by name, and there are no passwords: anyone can play under any name.
+pub struct Storage {
+    connection: Mutex<Connection>,
+}
+
+impl Storage {
+    /// Open the database at `path`, creating it if needed
+    pub fn open(path: &Path) -> rusqlite::Result<Self> {
+        Self::with_connection(Connection::open(path)?)
+    }
+
+    #[cfg(test)]
+    fn open_in_memory() -> rusqlite::Result<Self> {
+        Self::with_connection(Connection::open_in_memory()?)
+    }
+
+    fn with_connection(connection: Connection) -> rusqlite::Result<Self> {
+        connection.execute_batch(SCHEMA)?;
+        Ok(Self {
+            connection: Mutex::new(connection),
+        })
+    }
+
+    /// Record the game, and update the ratings of everyone that played it
+    /// under a name
+    pub fn record_game(&self, game: &FinishedGame) -> rusqlite::Result<()> {
+        let mut connection = self.connection.lock().unwrap_or_else(|e| e.into_inner());
+        let transaction = connection.transaction()?;
+        let finished_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since| since.as_secs() as i64);
+        transaction.execute(
+            "INSERT INTO games (finished_at, duration_ms, snaps, mistakes, seed)
+             VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![
+                finished_at,
+                game.duration.as_millis() as i64,
+                game.snaps as i64,
+                game.mistakes as i64,
+                game.seed.to_string(),
+            ],
+        )?;
+        let game_id = transaction.last_insert_rowid();
+
+        let mut ratings = vec![];
+        for name in &game.players {
+            let rating = match name {
+                Some(name) => {
+                    transaction.execute(
+                        "INSERT OR IGNORE INTO players (name, rating) VALUES (?1, ?2)",
+                        params![name, STARTING_RATING],
+                    )?;
+                    Some(transaction.query_row(
+                        "SELECT rating FROM players WHERE name = ?1",
+                        [name],
+                        |row| row.get(0),
+                    )?)
+                }
+                None => None,
+            };
+            ratings.push(rating);
+        }
+
+        let new_ratings = rate_game(&ratings, game.winner);
+        for (seat, (name, rating)) in game.players.iter().zip(new_ratings).enumerate() {
+            let won = seat == game.winner;
+            transaction.execute(
+                "INSERT INTO game_players (game_id, seat, name, won) VALUES (?1, ?2, ?3, ?4)",
+                params![game_id, seat as i64, name, won],
+            )?;
+            if let (Some(name), Some(rating)) = (name, rating) {
+                transaction.execute(
+                    "UPDATE players
+                     SET rating = ?2, games_played = games_played + 1, wins = wins + ?3
+                     WHERE name = ?1",
+                    params![name, rating, won],
+                )?;
+            }
+        }
+        transaction.commit()
+    }
+
+    /// The `limit` highest rated players, best first
+    pub fn leaderboard(&self, limit: usize) -> rusqlite::Result<Vec<Profile>> {
+        let connection = self.connection.lock().unwrap_or_else(|e| e.into_inner());
+        let mut statement = connection.prepare(
+            "SELECT name, rating, games_played, wins FROM players
+             WHERE games_played > 0
+             ORDER BY rating DESC, wins DESC, name
+             LIMIT ?1",
+        )?;
+        statement
+            .query_map([limit as i64], profile_from_row)?
+            .collect()
+    }
+}
+
+fn profile_from_row(row: &rusqlite::Row) -> rusqlite::Result<Profile> {
+    Ok(Profile {
+        name: row.get(0)?,
+        rating: row.get(1)?,
+        games_played: row.get(2)?,
+        wins: row.get(3)?,
+    })
+}
+
+/// Tidy up a name chosen by a player, or explain what's wrong with it
+pub fn validate_name(name: &str) -> Result<String, String> {
+    let name = name.trim();
+    if name.is_empty() || name.chars().count() > MAX_NAME_LENGTH {
+        return Err(format!(
+            "Names must be between 1 and {} characters long",
+            MAX_NAME_LENGTH
+        ));
+    }
+    if !name
+        .chars()
+        .all(|c| c.is_alphanumeric() || c == ' ' || c == '-' || c == '_')
+    {
+        return Err("Names can only use letters, numbers, spaces, - and _".to_string());
+    }
+    Ok(name.to_string())
+}
+
+/// Chance a player rated `rating` beats one rated `opponent`
+fn expected_score(rating: f64, opponent: f64) -> f64 {
+    1.0 / (1.0 + 10f64.powf((opponent - rating) / 400.0))
+}
+
+/// Elo ratings after a game, treating it as the winner beating each of the
+/// other players in turn. Players without a rating are left out, and don't
+/// count towards anyone else's; if the winner has no rating, nothing changes.
+fn rate_game(ratings: &[Option<f64>], winner: usize) -> Vec<Option<f64>> {
+    let Some(Some(winner_rating)) = ratings.get(winner).copied() else {
+        return ratings.to_vec();
+    };
+    let num_opponents = ratings.iter().flatten().count().saturating_sub(1).max(1);
+    let k = RATING_K_FACTOR / num_opponents as f64;
+    let mut new_ratings = ratings.to_vec();
+    for (seat, rating) in ratings.iter().enumerate() {
+        let Some(rating) = *rating else {
+            continue;
+        };
+        if seat == winner {
+            continue;
+        }
+        let change = k * (1.0 - expected_score(winner_rating, rating));
+        new_ratings[winner] = new_ratings[winner].map(|r| r + change);
+        new_ratings[seat] = Some(rating - change);
+    }
+    new_ratings
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn finished_game(players: &[Option<&str>], winner: usize) -> FinishedGame {
+        FinishedGame {
+            players: players.iter().map(|p| p.map(str::to_string)).collect(),
+            winner,
+            duration: Duration::from_secs(90),
+            snaps: 4,
+            mistakes: 1,
+            seed: u64::MAX,
+        }
+    }
+
+    #[test]
+    fn even_game_moves_ratings_by_half_k() {
+        let ratings = rate_game(&[Some(1200.0), Some(1200.0)], 1);
+        assert_eq!(ratings, vec![Some(1184.0), Some(1216.0)]);
+    }
+
+    #[test]
+    fn upsets_move_ratings_further() {
+        let [Some(favourite), Some(underdog)] = rate_game(&[Some(1600.0), Some(1200.0)], 1)[..]
+        else {
+            panic!()
+        };
+        assert!(1600.0 - favourite > 16.0);
+        assert!(underdog - 1200.0 > 16.0);
+        // Anonymous players aren't rated
+        assert_eq!(
+            rate_game(&[Some(1200.0), None], 0),
+            vec![Some(1200.0), None]
+        );
+    }
+
+    #[test]
+    fn leaderboard_ranks_by_rating() {
+        let storage = Storage::open_in_memory().unwrap();
+        storage
+            .record_game(&finished_game(&[Some("ann"), Some("bob")], 0))
+            .unwrap();
+        storage
+            .record_game(&finished_game(&[Some("ann"), None, Some("cat")], 0))
+            .unwrap();
+
+        let leaderboard = storage.leaderboard(10).unwrap();
+        let names: Vec<&str> = leaderboard.iter().map(|p| p.name.as_str()).collect();
+        assert_eq!(names, vec!["ann", "cat", "bob"]);
+        assert_eq!((leaderboard[0].games_played, leaderboard[0].wins), (2, 2));
+        assert_eq!(storage.leaderboard(1).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn names_are_checked() {
+        assert_eq!(validate_name("  Ann Smith "), Ok("Ann Smith".to_string()));
+        assert!(validate_name("").is_err());
+        assert!(validate_name("Robert'); DROP TABLE players;--").is_err());
+        assert!(validate_name(&"a".repeat(MAX_NAME_LENGTH + 1)).is_err());
+    }
+}
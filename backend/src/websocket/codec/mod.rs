@@ -0,0 +1,188 @@
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use warp::ws::Message;
+
+use crate::message::ProtocolError;
+
+/// How messages are turned into websocket frames and back again
+pub trait Codec {
+    fn encode<T: Serialize>(message: &T) -> Result<Message, String>;
+    fn decode<T: DeserializeOwned>(frame: &Message) -> Result<T, ProtocolError>;
+}
+
+/// Text frames of JSON, which is what browsers find easiest
+pub struct Json;
+
+/// Binary frames of MessagePack. Structs are encoded as maps, so the messages
+/// have the same shape as in JSON.
+pub struct MessagePack;
+
+/// Binary frames of CBOR
+pub struct Cbor;
+
+impl Codec for Json {
+    fn encode<T: Serialize>(message: &T) -> Result<Message, String> {
+        serde_json::to_string(message)
+            .map(Message::text)
+            .map_err(|e| e.to_string())
+    }
+
+    fn decode<T: DeserializeOwned>(frame: &Message) -> Result<T, ProtocolError> {
+        let Ok(raw_string) = frame.to_str() else {
+            return Err(ProtocolError::Malformed);
+        };
+        serde_json::from_str(raw_string).map_err(|e| match e.classify() {
+            // Valid JSON, but not a message we know
+            serde_json::error::Category::Data => ProtocolError::UnknownVariant,
+            _ => ProtocolError::Malformed,
+        })
+    }
+}
+
+impl Codec for MessagePack {
+    fn encode<T: Serialize>(message: &T) -> Result<Message, String> {
+        rmp_serde::to_vec_named(message)
+            .map(Message::binary)
+            .map_err(|e| e.to_string())
+    }
+
+    fn decode<T: DeserializeOwned>(frame: &Message) -> Result<T, ProtocolError> {
+        if !frame.is_binary() {
+            return Err(ProtocolError::Malformed);
+        }
+        rmp_serde::from_slice(frame.as_bytes()).map_err(|e| match e {
+            // Serde's own complaints, about well-formed data
+            rmp_serde::decode::Error::Syntax(_) => ProtocolError::UnknownVariant,
+            _ => ProtocolError::Malformed,
+        })
+    }
+}
+
+impl Codec for Cbor {
+    fn encode<T: Serialize>(message: &T) -> Result<Message, String> {
+        let mut bytes = vec![];
+        ciborium::into_writer(message, &mut bytes).map_err(|e| e.to_string())?;
+        Ok(Message::binary(bytes))
+    }
+
+    fn decode<T: DeserializeOwned>(frame: &Message) -> Result<T, ProtocolError> {
+        if !frame.is_binary() {
+            return Err(ProtocolError::Malformed);
+        }
+        ciborium::from_reader(frame.as_bytes()).map_err(|e| match e {
+            ciborium::de::Error::Semantic(..) => ProtocolError::UnknownVariant,
+            _ => ProtocolError::Malformed,
+        })
+    }
+}
+
+/// Which codec a connection uses. Clients choose with the `encoding` query
+/// parameter, or by offering it as a websocket subprotocol.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Encoding {
+    #[default]
+    Json,
+    #[serde(rename = "msgpack")]
+    MessagePack,
+    Cbor,
+}
+
+impl Encoding {
+    /// The encoding's name, as used in query strings and subprotocols
+    pub fn name(self) -> &'static str {
+        match self {
+            Encoding::Json => "json",
+            Encoding::MessagePack => "msgpack",
+            Encoding::Cbor => "cbor",
+        }
+    }
+
+    /// The first encoding we support from the list of subprotocols offered in
+    /// a `Sec-WebSocket-Protocol` header
+    pub fn from_subprotocols(header: &str) -> Option<Encoding> {
+        header.split(',').map(str::trim).find_map(|offered| {
+            [Encoding::Json, Encoding::MessagePack, Encoding::Cbor]
+                .into_iter()
+                .find(|encoding| encoding.name() == offered)
+        })
+    }
+
+    pub fn encode<T: Serialize>(self, message: &T) -> Result<Message, String> {
+        match self {
+            Encoding::Json => Json::encode(message),
+            Encoding::MessagePack => MessagePack::encode(message),
+            Encoding::Cbor => Cbor::encode(message),
+        }
+    }
+
+    pub fn decode<T: DeserializeOwned>(self, frame: &Message) -> Result<T, ProtocolError> {
+        match self {
+            Encoding::Json => Json::decode(frame),
+            Encoding::MessagePack => MessagePack::decode(frame),
+            Encoding::Cbor => Cbor::decode(frame),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, PartialEq, Deserialize, Serialize)]
+    enum Sample {
+        Draw(u32),
+        Named { name: String },
+        PlayAgain,
+    }
+
+    #[test]
+    fn messages_survive_each_encoding() {
+        let messages = [
+            Sample::Draw(120),
+            Sample::Named {
+                name: "ann".to_string(),
+            },
+            Sample::PlayAgain,
+        ];
+        for encoding in [Encoding::Json, Encoding::MessagePack, Encoding::Cbor] {
+            for message in &messages {
+                let frame = encoding.encode(message).unwrap();
+                assert_eq!(frame.is_binary(), encoding != Encoding::Json);
+                assert_eq!(encoding.decode::<Sample>(&frame).as_ref(), Ok(message));
+            }
+        }
+    }
+
+    #[test]
+    fn bad_frames_classified() {
+        for encoding in [Encoding::Json, Encoding::MessagePack, Encoding::Cbor] {
+            let unknown = encoding.encode(&"Dance").unwrap();
+            assert_eq!(
+                encoding.decode::<Sample>(&unknown),
+                Err(ProtocolError::UnknownVariant),
+                "{:?}",
+                encoding
+            );
+            let garbage = match encoding {
+                Encoding::Json => Message::text("{"),
+                _ => Message::binary(vec![0xc1]),
+            };
+            assert_eq!(
+                encoding.decode::<Sample>(&garbage),
+                Err(ProtocolError::Malformed),
+                "{:?}",
+                encoding
+            );
+        }
+    }
+
+    #[test]
+    fn encoding_chosen_from_subprotocols() {
+        assert_eq!(
+            Encoding::from_subprotocols("graphql-ws, cbor, msgpack"),
+            Some(Encoding::Cbor)
+        );
+        assert_eq!(Encoding::from_subprotocols("graphql-ws"), None);
+    }
+}
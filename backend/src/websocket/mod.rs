@@ -1,27 +1,212 @@
 use std::fmt;
 use std::marker::PhantomData;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, Instant};
 
-use futures_util::{SinkExt, StreamExt, TryFutureExt, select_biased};
+use futures_util::{SinkExt, StreamExt};
 
 use serde::{Deserialize, Serialize};
 use tokio::sync::mpsc;
-use tokio_stream::wrappers::ReceiverStream;
+use tokio_stream::wrappers::UnboundedReceiverStream;
 use warp::ws::Message;
 
+use crate::message::ProtocolError;
+
+mod codec;
+
+pub use codec::Encoding;
+
+/// Most messages that can be waiting to go out to a client before the
+/// handler's `OverflowPolicy` kicks in. We don't have high throughput, so a
+/// client this far behind is struggling.
+const QUEUE_LIMIT: usize = 25;
+
+/// Most messages that can be waiting for a client during its grace period,
+/// so a client that never catches up can't use up the server's memory
+const GRACE_QUEUE_LIMIT: usize = 4 * QUEUE_LIMIT;
+
+/// Unreadable messages a client can send before we stop listening to it
+const MAX_PROTOCOL_ERRORS: usize = 10;
+
+/// How often we ping the client, unless told otherwise
+pub const DEFAULT_PING_INTERVAL: Duration = Duration::from_secs(5);
+
+/// How long the client can go without answering before we give up on it,
+/// unless told otherwise
+pub const DEFAULT_PONG_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// How we check a connection is still alive. Pings measure the round-trip
+/// time, and a client that hasn't sent anything, not even a pong, for
+/// `pong_timeout` is disconnected. That stops half-open connections holding on
+/// to their seat forever.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Heartbeat {
+    pub ping_interval: Duration,
+    pub pong_timeout: Duration,
+}
+
+impl Default for Heartbeat {
+    fn default() -> Self {
+        Self {
+            ping_interval: DEFAULT_PING_INTERVAL,
+            pong_timeout: DEFAULT_PONG_TIMEOUT,
+        }
+    }
+}
+
+/// What to do when a client falls so far behind that `QUEUE_LIMIT` messages
+/// are waiting for it. Sending never blocks, so one slow client can't hold up
+/// everyone else.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum OverflowPolicy {
+    /// Keep queueing for up to this long, then disconnect the client if it
+    /// still hasn't caught up. Sends never wait; the queue grows for the
+    /// grace period, up to `GRACE_QUEUE_LIMIT` messages.
+    Grace(Duration),
+    /// Disconnect the client straight away
+    Disconnect,
+    /// Throw away everything still queued. The send fails with
+    /// `SendError::Full`, and the caller should follow up with the whole
+    /// state so the client can start afresh.
+    Resync,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum SendError {
+    /// The connection has gone
+    Closed,
+    /// The client is too far behind; see `OverflowPolicy`
+    Full,
+    /// The message couldn't be serialized
+    Serialize,
+}
+
+impl fmt::Display for SendError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SendError::Closed => write!(f, "connection closed"),
+            SendError::Full => write!(f, "client too far behind"),
+            SendError::Serialize => write!(f, "message could not be serialized"),
+        }
+    }
+}
+
+/// Who is on the other end of a connection, for logging
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Peer {
+    /// A player or spectator, by their user ID
+    User(usize),
+    /// Someone in the quickplay queue, who doesn't have a user ID yet
+    Queued(usize),
+    /// Someone being turned away with a message
+    TurnedAway,
+}
+
+impl fmt::Display for Peer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Peer::User(user_id) => write!(f, "user {}", user_id),
+            Peer::Queued(ticket) => write!(f, "quickplay ticket {}", ticket),
+            Peer::TurnedAway => write!(f, "turned away connection"),
+        }
+    }
+}
+
 /// Abstraction to handle websocket connections
 pub struct WebSocketHandler<I: for<'de> Deserialize<'de>, O: Serialize> {
-    send_channel: mpsc::Sender<Message>,
-    cancellation_token: tokio_util::sync::CancellationToken,
+    outbox: Arc<Outbox>,
+    round_trip: RoundTripTime,
     _phantom: PhantomData<(I, O)>,
 }
 
-impl<I: for<'de> Deserialize<'de> + Send, O: Serialize + fmt::Debug> WebSocketHandler<I, O> {
-    /// Create a new websocket connection. `user_id` is for logging only.
-    /// Websocket will disconnect when either client disconnects or `.close()` is called.
+/// The sending side of a connection. The reader task keeps a weak reference,
+/// so it can answer bad messages without keeping the connection open once
+/// the handler is dropped.
+struct Outbox {
+    peer: Peer,
+    send_channel: mpsc::UnboundedSender<(u64, Message)>,
+    cancellation_token: tokio_util::sync::CancellationToken,
+    backlog: Arc<Backlog>,
+    overflow: OverflowPolicy,
+    encoding: Encoding,
+    /// Unreadable messages the client has sent so far
+    violations: AtomicUsize,
+}
+
+/// Messages waiting to go out, shared between the handler and its writer
+/// task. Each message is tagged with the generation it was queued in, so a
+/// resync can throw away everything before it.
+#[derive(Default)]
+struct Backlog {
+    /// Messages from the current generation not sent yet
+    queued: AtomicUsize,
+    generation: AtomicU64,
+    /// When the queue went over the limit, if it still is
+    overflowed_at: Mutex<Option<Instant>>,
+}
+
+impl Backlog {
+    fn sent(&self) {
+        let previous = self
+            .queued
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| {
+                Some(n.saturating_sub(1))
+            })
+            .unwrap_or_default();
+        if previous <= QUEUE_LIMIT {
+            *self.overflowed_at.lock().unwrap_or_else(|e| e.into_inner()) = None;
+        }
+    }
+
+    /// Start a new generation, forgetting everything queued so far
+    fn clear(&self) {
+        self.generation.fetch_add(1, Ordering::Relaxed);
+        self.queued.store(0, Ordering::Relaxed);
+        *self.overflowed_at.lock().unwrap_or_else(|e| e.into_inner()) = None;
+    }
+}
+
+/// Smoothed round-trip time of a connection, shared between the handler and
+/// its tasks. Stored in microseconds, with zero meaning "not measured yet".
+#[derive(Clone, Default)]
+struct RoundTripTime(Arc<AtomicU64>);
+
+impl RoundTripTime {
+    fn get(&self) -> Option<Duration> {
+        match self.0.load(Ordering::Relaxed) {
+            0 => None,
+            micros => Some(Duration::from_micros(micros)),
+        }
+    }
+
+    /// Fold a new measurement into the estimate, weighting it like TCP does
+    fn record(&self, sample: Duration) {
+        let sample = u64::try_from(sample.as_micros()).unwrap_or(u64::MAX).max(1);
+        let smoothed = match self.0.load(Ordering::Relaxed) {
+            0 => sample,
+            previous => (previous * 7 + sample) / 8,
+        };
+        self.0.store(smoothed, Ordering::Relaxed);
+    }
+}
+
+impl<I, O> WebSocketHandler<I, O>
+where
+    I: for<'de> Deserialize<'de> + Send,
+    O: Serialize + fmt::Debug + From<ProtocolError> + 'static,
+{
+    /// Create a new websocket connection. `peer` is for logging only.
+    /// Websocket will disconnect when either client disconnects, stops
+    /// answering pings, falls too far behind for `overflow`, or `.close()`
+    /// is called. Messages both ways are framed with `encoding`.
     /// The types are a bit upsetting but seem to work fine.
     pub fn new<EmptyFuture, EmptyFuture2>(
         ws: warp::ws::WebSocket,
-        user_id: usize,
+        peer: Peer,
+        heartbeat: Heartbeat,
+        overflow: OverflowPolicy,
+        encoding: Encoding,
         mut on_message: impl FnMut(I) -> EmptyFuture + Send + 'static,
         mut on_disconnect: impl FnMut() -> EmptyFuture2 + Send + 'static,
     ) -> Self
@@ -31,91 +216,516 @@ impl<I: for<'de> Deserialize<'de> + Send, O: Serialize + fmt::Debug> WebSocketHa
     {
         let (mut ws_out, mut ws_in) = ws.split();
 
-        // Use an channel bound to 25 messages to handle buffering and flushing of messages.
-        // We don't have high throughput so 25 messages should be ample.
-        let (send_channel, receive_channel) = mpsc::channel(25);
-        let mut receive_channel = ReceiverStream::new(receive_channel);
+        // The channel itself is unbounded; the backlog keeps count of what's
+        // in it so the overflow policy can step in.
+        let (send_channel, receive_channel) = mpsc::unbounded_channel();
+        let mut receive_channel = UnboundedReceiverStream::new(receive_channel);
+        let backlog = Arc::new(Backlog::default());
 
         let cancellation_token = tokio_util::sync::CancellationToken::new();
+        let round_trip = RoundTripTime::default();
+        let outbox = Arc::new(Outbox {
+            peer,
+            send_channel,
+            cancellation_token: cancellation_token.clone(),
+            backlog: backlog.clone(),
+            overflow,
+            encoding,
+            violations: AtomicUsize::new(0),
+        });
+
+        // Pings carry the time they were sent, relative to this instant, so we
+        // can work out the round-trip time from the pong.
+        let created_at = Instant::now();
 
         // Spawn a new task to read from the send channel and send messages
-        // through the websocket.
+        // through the websocket. This task also sends the pings.
         {
             let cancellation_token = cancellation_token.clone();
+            let backlog = backlog.clone();
+            let mut ping_interval = tokio::time::interval(heartbeat.ping_interval);
             tokio::task::spawn(async move {
-                while let Some(message) = tokio::select! {
+                // Queued messages come with their generation; pings don't
+                while let Some((generation, message)) = tokio::select! {
                     biased;
-                    maybe_message = receive_channel.next() => maybe_message,
+                    maybe_message = receive_channel.next() => {
+                        maybe_message.map(|(generation, message)| (Some(generation), message))
+                    },
+                    _ = ping_interval.tick() => Some((None, ping_message(created_at))),
                     _ = cancellation_token.cancelled() => None,
                 } {
-                    ws_out
-                        .send(message)
-                        .unwrap_or_else(|e| {
+                    let current = backlog.generation.load(Ordering::Relaxed);
+                    if generation.is_some_and(|generation| generation != current) {
+                        // Superseded by a resync
+                        continue;
+                    }
+                    // A client that isn't reading can leave this stuck, so
+                    // don't wait on it once we're closing
+                    tokio::select! {
+                        biased;
+                        result = ws_out.send(message) => if let Err(e) = result {
                             eprintln!("websocket send error: {}", e);
                             cancellation_token.cancel();
-                        })
-                        .await;
+                        },
+                        _ = cancellation_token.cancelled() => break,
+                    }
+                    if generation.is_some() {
+                        backlog.sent();
+                    }
                 }
+                // Let the client know we're done, rather than just dropping
+                // the connection
+                _ = ws_out.close().await;
             })
         };
 
         // Spawn a new task to read incoming messages and perform the
-        // `on_message` function. This function also performs the
+        // `on_message` function. This task gives up on the client if it goes
+        // quiet, tells it about bad messages, and performs the
         // `on_disconnect` cleanup.
         {
             let cancellation_token = cancellation_token.clone();
+            let round_trip = round_trip.clone();
+            let outbox = Arc::downgrade(&outbox);
             tokio::task::spawn(async move {
                 while let Some(result) = tokio::select! {
                     biased;
-                    maybe_result = ws_in.next() => maybe_result,
+                    maybe_result = tokio::time::timeout(heartbeat.pong_timeout, ws_in.next()) => {
+                        maybe_result.unwrap_or_else(|_| {
+                            println!("Disconnecting {}: stopped answering pings", peer);
+                            None
+                        })
+                    },
                     _ = cancellation_token.cancelled() => None,
                 } {
-                    match parse_websocket_message(result) {
+                    let Ok(message) = result else {
+                        println!("Websocket error from {}", peer);
+                        continue;
+                    };
+                    if message.is_pong() {
+                        if let Some(sample) = round_trip_from_pong(&message, created_at) {
+                            round_trip.record(sample);
+                        }
+                        continue;
+                    }
+                    // Pings are answered for us, and a close ends the stream
+                    if message.is_ping() || message.is_close() {
+                        continue;
+                    }
+                    match encoding.decode(&message) {
                         Ok(message) => on_message(message).await,
-                        Err(_) => {
-                            println!("Bad message from user {}", user_id)
+                        Err(error) => {
+                            println!("Bad message from {}: {}", peer, error);
+                            if let Some(outbox) = Weak::upgrade(&outbox) {
+                                _ = outbox.reject::<O>(error);
+                            }
                         }
                     }
                 }
-                println!("Disconnecting user {}", user_id);
+                println!("Disconnecting {}", peer);
+                // Stop the writer too, if it's still going
+                cancellation_token.cancel();
                 on_disconnect().await;
             })
         };
 
         WebSocketHandler {
-            send_channel,
-            cancellation_token,
+            outbox,
+            round_trip,
             _phantom: PhantomData,
         }
     }
 
-    pub fn send(&self, message: O) -> Result<(), ()> {
-        let Ok(s) = serde_json::to_string(&message) else {
-            println!("Could not serialize message: {:?}", &message);
-            return Err(());
-        };
-        match self.send_channel.try_send(Message::text(s)) {
-            Ok(()) => Ok(()),
-            Err(_) => Err(()),
-        }
+    /// Smoothed round-trip time of this connection, once it has been measured
+    pub fn round_trip(&self) -> Option<Duration> {
+        self.round_trip.get()
+    }
+
+    /// Queue a message for the client. If it's too far behind, the
+    /// handler's `OverflowPolicy` decides what happens.
+    pub fn send(&self, message: O) -> Result<(), SendError> {
+        self.outbox.send(self.outbox.encode(&message)?)
+    }
+
+    /// Tell the client its message was rejected. Clients that keep breaking
+    /// the protocol are disconnected.
+    pub fn reject(&self, error: ProtocolError) -> Result<(), SendError> {
+        self.outbox.reject::<O>(error)
     }
 
     pub fn close(&self) {
+        self.outbox.close();
+    }
+}
+
+impl Outbox {
+    fn send(&self, message: Message) -> Result<(), SendError> {
+        if self.cancellation_token.is_cancelled() {
+            return Err(SendError::Closed);
+        }
+        if self.backlog.queued.load(Ordering::Relaxed) >= QUEUE_LIMIT {
+            match self.overflow {
+                OverflowPolicy::Grace(grace) => {
+                    if self.backlog.queued.load(Ordering::Relaxed) >= GRACE_QUEUE_LIMIT {
+                        println!("Disconnecting {}: fell too far behind", self.peer);
+                        self.close();
+                        return Err(SendError::Full);
+                    }
+                    let mut overflowed_at = self
+                        .backlog
+                        .overflowed_at
+                        .lock()
+                        .unwrap_or_else(|e| e.into_inner());
+                    if overflowed_at.is_none() {
+                        let started = Instant::now();
+                        *overflowed_at = Some(started);
+                        self.disconnect_after(grace, started);
+                    }
+                }
+                OverflowPolicy::Disconnect => {
+                    println!("Disconnecting {}: fell too far behind", self.peer);
+                    self.close();
+                    return Err(SendError::Full);
+                }
+                OverflowPolicy::Resync => {
+                    self.backlog.clear();
+                    return Err(SendError::Full);
+                }
+            }
+        }
+        let generation = self.backlog.generation.load(Ordering::Relaxed);
+        self.backlog.queued.fetch_add(1, Ordering::Relaxed);
+        self.send_channel
+            .send((generation, message))
+            .map_err(|_| SendError::Closed)
+    }
+
+    fn reject<O: Serialize + fmt::Debug + From<ProtocolError>>(
+        &self,
+        error: ProtocolError,
+    ) -> Result<(), SendError> {
+        let result = self.send(self.encode(&O::from(error))?);
+        // Breaking the rules of the game is just playing badly
+        if !error.is_unreadable() {
+            return result;
+        }
+        let violations = self.violations.fetch_add(1, Ordering::Relaxed) + 1;
+        if violations >= MAX_PROTOCOL_ERRORS {
+            println!(
+                "Disconnecting {}: sent {} unreadable messages",
+                self.peer, violations
+            );
+            self.close();
+        }
+        result
+    }
+
+    fn close(&self) {
         self.cancellation_token.cancel();
     }
+
+    /// Disconnect the client once `grace` is up, unless it has caught up
+    /// since falling behind at `started`. This doesn't rely on anything else
+    /// being sent to the client in the meantime.
+    fn disconnect_after(&self, grace: Duration, started: Instant) {
+        let peer = self.peer;
+        let backlog = self.backlog.clone();
+        let cancellation_token = self.cancellation_token.clone();
+        tokio::task::spawn(async move {
+            tokio::select! {
+                _ = tokio::time::sleep(grace) => {}
+                _ = cancellation_token.cancelled() => return,
+            }
+            let overflowed_at = *backlog
+                .overflowed_at
+                .lock()
+                .unwrap_or_else(|e| e.into_inner());
+            if overflowed_at == Some(started) {
+                println!("Disconnecting {}: fell too far behind", peer);
+                cancellation_token.cancel();
+            }
+        });
+    }
+
+    fn encode<O: Serialize + fmt::Debug>(&self, message: &O) -> Result<Message, SendError> {
+        self.encoding.encode(message).map_err(|_| {
+            println!("Could not serialize message: {:?}", message);
+            SendError::Serialize
+        })
+    }
 }
 
-fn parse_websocket_message<I: for<'de> Deserialize<'de>, _E>(
-    result: Result<Message, _E>,
-) -> Result<I, ()> {
-    let Ok(raw_message) = result else {
-        return Err(());
-    };
-    let Ok(raw_string) = raw_message.to_str() else {
-        return Err(());
-    };
-    let Ok(message) = serde_json::from_str(raw_string) else {
-        return Err(());
+fn ping_message(created_at: Instant) -> Message {
+    let sent_micros = u64::try_from(created_at.elapsed().as_micros()).unwrap_or(u64::MAX);
+    Message::ping(sent_micros.to_be_bytes().to_vec())
+}
+
+/// Time since the ping this pong is answering was sent, if it's one of ours
+fn round_trip_from_pong(pong: &Message, created_at: Instant) -> Option<Duration> {
+    let sent_micros = u64::from_be_bytes(pong.as_bytes().try_into().ok()?);
+    let sent_at = created_at + Duration::from_micros(sent_micros);
+    Instant::now().checked_duration_since(sent_at)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use warp::Filter;
+
+    type TestHandler = WebSocketHandler<String, TestOutput>;
+
+    #[derive(Debug, Serialize)]
+    enum TestOutput {
+        Text(String),
+        Rejected(ProtocolError),
+    }
+
+    impl From<ProtocolError> for TestOutput {
+        fn from(error: ProtocolError) -> Self {
+            TestOutput::Rejected(error)
+        }
+    }
+
+    fn text(s: &str) -> TestOutput {
+        TestOutput::Text(s.to_string())
+    }
+
+    /// Next message that isn't a ping
+    async fn next_text(client: &mut warp::test::WsClient) -> String {
+        loop {
+            let message = client.recv().await.unwrap();
+            if let Ok(text) = message.to_str() {
+                return text.to_string();
+            }
+        }
+    }
+
+    const HEARTBEAT: Heartbeat = Heartbeat {
+        ping_interval: Duration::from_millis(20),
+        pong_timeout: Duration::from_millis(100),
     };
-    return Ok(message);
+
+    /// Route accepting websockets, which hands each handler to the test and
+    /// notes when it disconnects
+    fn route(
+        overflow: OverflowPolicy,
+        handlers: mpsc::UnboundedSender<TestHandler>,
+        disconnects: mpsc::UnboundedSender<()>,
+    ) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+        warp::ws().map(move |ws: warp::ws::Ws| {
+            let handlers = handlers.clone();
+            let disconnects = disconnects.clone();
+            ws.on_upgrade(move |socket| async move {
+                let on_disconnect = move || {
+                    _ = disconnects.send(());
+                    async {}
+                };
+                let handler = WebSocketHandler::new(
+                    socket,
+                    Peer::User(0),
+                    HEARTBEAT,
+                    overflow,
+                    Encoding::Json,
+                    async |_| {},
+                    on_disconnect,
+                );
+                _ = handlers.send(handler);
+            })
+        })
+    }
+
+    #[tokio::test]
+    async fn answered_pings_keep_connection_alive() {
+        let (handlers_tx, mut handlers) = mpsc::unbounded_channel();
+        let (disconnects_tx, mut disconnects) = mpsc::unbounded_channel();
+        let _client = warp::test::ws()
+            .handshake(route(
+                OverflowPolicy::Disconnect,
+                handlers_tx,
+                disconnects_tx,
+            ))
+            .await
+            .unwrap();
+        let handler = handlers.recv().await.unwrap();
+
+        tokio::time::sleep(HEARTBEAT.pong_timeout * 3).await;
+        assert!(disconnects.try_recv().is_err());
+        assert!(handler.round_trip().is_some());
+    }
+
+    #[tokio::test]
+    async fn silent_client_disconnected() {
+        let (handlers_tx, mut handlers) = mpsc::unbounded_channel();
+        let (disconnects_tx, mut disconnects) = mpsc::unbounded_channel();
+        let (addr, server) = warp::serve(route(
+            OverflowPolicy::Disconnect,
+            handlers_tx,
+            disconnects_tx,
+        ))
+        .bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+
+        // A client that finishes the handshake, then never answers
+        let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+        stream
+            .write_all(
+                b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: upgrade\r\n\
+                  Upgrade: websocket\r\nSec-WebSocket-Version: 13\r\n\
+                  Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\r\n",
+            )
+            .await
+            .unwrap();
+        let mut status = [0; 12];
+        stream.read_exact(&mut status).await.unwrap();
+        assert_eq!(&status, b"HTTP/1.1 101");
+        let handler = handlers.recv().await.unwrap();
+
+        let disconnected = tokio::time::timeout(Duration::from_secs(2), disconnects.recv()).await;
+        assert_eq!(disconnected, Ok(Some(())));
+        assert!(handler.round_trip().is_none());
+    }
+
+    /// Queue up messages until the handler is one away from its limit. The
+    /// writer doesn't get to run until the test next awaits.
+    fn fill_queue(handler: &TestHandler) {
+        for i in 0..QUEUE_LIMIT {
+            assert_eq!(handler.send(text(&i.to_string())), Ok(()));
+        }
+    }
+
+    #[tokio::test]
+    async fn slow_client_disconnected() {
+        let (handlers_tx, mut handlers) = mpsc::unbounded_channel();
+        let (disconnects_tx, mut disconnects) = mpsc::unbounded_channel();
+        let _client = warp::test::ws()
+            .handshake(route(
+                OverflowPolicy::Disconnect,
+                handlers_tx,
+                disconnects_tx,
+            ))
+            .await
+            .unwrap();
+        let handler = handlers.recv().await.unwrap();
+
+        fill_queue(&handler);
+        assert_eq!(handler.send(text("late")), Err(SendError::Full));
+        assert_eq!(handler.send(text("later")), Err(SendError::Closed));
+        let disconnected = tokio::time::timeout(Duration::from_secs(2), disconnects.recv()).await;
+        assert_eq!(disconnected, Ok(Some(())));
+    }
+
+    #[tokio::test]
+    async fn slow_client_resynced() {
+        let (handlers_tx, mut handlers) = mpsc::unbounded_channel();
+        let (disconnects_tx, _disconnects) = mpsc::unbounded_channel();
+        let mut client = warp::test::ws()
+            .handshake(route(OverflowPolicy::Resync, handlers_tx, disconnects_tx))
+            .await
+            .unwrap();
+        let handler = handlers.recv().await.unwrap();
+
+        fill_queue(&handler);
+        assert_eq!(handler.send(text("late")), Err(SendError::Full));
+        assert_eq!(handler.send(text("state")), Ok(()));
+        // Everything queued before the resync was dropped
+        assert_eq!(next_text(&mut client).await, r#"{"Text":"state"}"#);
+    }
+
+    #[tokio::test]
+    async fn slow_client_given_time() {
+        let (handlers_tx, mut handlers) = mpsc::unbounded_channel();
+        let (disconnects_tx, _disconnects) = mpsc::unbounded_channel();
+        let _client = warp::test::ws()
+            .handshake(route(
+                OverflowPolicy::Grace(Duration::from_secs(60)),
+                handlers_tx,
+                disconnects_tx,
+            ))
+            .await
+            .unwrap();
+        let handler = handlers.recv().await.unwrap();
+
+        fill_queue(&handler);
+        assert_eq!(handler.send(text("late")), Ok(()));
+        assert!(!handler.outbox.cancellation_token.is_cancelled());
+    }
+
+    /// An outbox with nothing taking messages off its queue, like a client
+    /// that has stopped reading
+    fn stalled_outbox(
+        overflow: OverflowPolicy,
+    ) -> (Outbox, mpsc::UnboundedReceiver<(u64, Message)>) {
+        let (send_channel, receive_channel) = mpsc::unbounded_channel();
+        let outbox = Outbox {
+            peer: Peer::User(0),
+            send_channel,
+            cancellation_token: tokio_util::sync::CancellationToken::new(),
+            backlog: Arc::default(),
+            overflow,
+            encoding: Encoding::Json,
+            violations: AtomicUsize::new(0),
+        };
+        (outbox, receive_channel)
+    }
+
+    #[tokio::test]
+    async fn stalled_client_disconnected_after_grace() {
+        let grace = Duration::from_millis(50);
+        let (outbox, _queue) = stalled_outbox(OverflowPolicy::Grace(grace));
+        for _ in 0..=QUEUE_LIMIT {
+            assert_eq!(outbox.send(Message::text("late")), Ok(()));
+        }
+        assert!(!outbox.cancellation_token.is_cancelled());
+
+        // Nothing else is sent, but the grace period still runs out
+        tokio::time::sleep(grace * 3).await;
+        assert!(outbox.cancellation_token.is_cancelled());
+    }
+
+    #[tokio::test]
+    async fn grace_queue_capped() {
+        let (outbox, _queue) = stalled_outbox(OverflowPolicy::Grace(Duration::from_secs(60)));
+        for _ in 0..GRACE_QUEUE_LIMIT {
+            assert_eq!(outbox.send(Message::text("late")), Ok(()));
+        }
+        assert_eq!(outbox.send(Message::text("later")), Err(SendError::Full));
+        assert!(outbox.cancellation_token.is_cancelled());
+    }
+
+    #[tokio::test]
+    async fn bad_messages_rejected() {
+        let (handlers_tx, mut handlers) = mpsc::unbounded_channel();
+        let (disconnects_tx, mut disconnects) = mpsc::unbounded_channel();
+        let mut client = warp::test::ws()
+            .handshake(route(OverflowPolicy::Resync, handlers_tx, disconnects_tx))
+            .await
+            .unwrap();
+        let handler = handlers.recv().await.unwrap();
+
+        // Mistakes in the game don't count against the client
+        for _ in 0..MAX_PROTOCOL_ERRORS {
+            _ = handler.reject(ProtocolError::NotYourTurn);
+            next_text(&mut client).await;
+        }
+        client.send_text("not json").await;
+        assert_eq!(
+            next_text(&mut client).await,
+            r#"{"Rejected":"Malformed"}"#
+        );
+        client.send_text(r#"{"Draw":100}"#).await;
+        assert_eq!(
+            next_text(&mut client).await,
+            r#"{"Rejected":"UnknownVariant"}"#
+        );
+        client.send_text(r#""fine""#).await;
+        assert!(disconnects.try_recv().is_err());
+
+        // Keep it up, and the connection is closed
+        for _ in 2..MAX_PROTOCOL_ERRORS {
+            client.send_text("not json").await;
+        }
+        let disconnected = tokio::time::timeout(Duration::from_secs(2), disconnects.recv()).await;
+        assert_eq!(disconnected, Ok(Some(())));
+    }
 }
@@ -20,6 +20,8 @@ type alias Table = {
   , opponentDeckOffset: (Float, Float)
   -- For wiggling the cards on invalid draws
   , invalidDrawCount: Int
+  -- Time left until the server lets us draw again, counting down
+  , drawCooldownMs: Int
   }
 
 newTable : Game.Events.PlayerNumber -> Table
@@ -35,6 +37,7 @@ newTable playerNumber = {
   , yourDeckOffset = (0, 0)
   , opponentDeckOffset = (0, 0)
   , invalidDrawCount = 0
+  , drawCooldownMs = 0
   }
 
 playerFromNumber : Table -> Game.Events.PlayerNumber -> Player
@@ -50,6 +53,18 @@ takeCenter table playerNumber =
     You -> { tableEmptyCenter | yourNumCards = table.yourNumCards + cardsTaken }
     Opponent -> { tableEmptyCenter | opponentNumCards = table.opponentNumCards + cardsTaken }
 
+-- Put the table back how the server says it is
+restoreTable : Table -> Game.Events.Snapshot -> Table
+restoreTable table snapshot =
+  let handSize index = snapshot.handSizes |> List.drop index |> List.head |> Maybe.withDefault 0
+      (yours, theirs) = if table.yourNumber == Game.Events.One then (handSize 0, handSize 1) else (handSize 1, handSize 0)
+  in { table
+    | yourNumCards = yours
+    , opponentNumCards = theirs
+    , centerDeck = snapshot.centerPile
+    , cardDrawnFrom = Nothing
+    }
+
 drawCard : Table -> Game.Events.PlayerNumber -> Game.Cards.Card -> Table
 drawCard table playerNumber card =
   let tableNewCenter = { table | centerDeck = table.centerDeck ++ [card] }
@@ -70,13 +85,48 @@ updateTable event table = case event of
             _ -> table.eventLog
         , invalidDrawCount = table.invalidDrawCount + 1
         }
+  Game.Events.DrawTooSoon info -> { table | drawCooldownMs = info.waitMs }
   Game.Events.PlayerTakesCenter playerNumber -> takeCenter table playerNumber
+  Game.Events.GameState snapshot -> restoreTable table snapshot
   Game.Events.GameRestarted -> newTable table.yourNumber
   Game.Events.OtherPlayerResponded response -> { table
     | eventLog = table.eventLog ++ [renderUserEvent table response.player response.action response.isMistake]
     }
+  Game.Events.MatchScore score -> { table
+    | eventLog = table.eventLog ++ [renderScore table score.roundWins score.bestOf]
+    }
   _ -> table
 
+renderScore : Table -> List Int -> Int -> String
+renderScore table roundWins bestOf =
+  case roundWins of
+    [ first, second ] ->
+      let (yours, theirs) = if table.yourNumber == Game.Events.One then (first, second) else (second, first)
+      in "Score: " ++ String.fromInt yours ++ " - " ++ String.fromInt theirs ++ " (best of " ++ String.fromInt bestOf ++ ")"
+    _ -> ""
+
+renderStats : Table -> List Game.Events.PlayerStats -> List String
+renderStats table stats =
+  case stats of
+    [ first, second ] ->
+      let (yours, theirs) = if table.yourNumber == Game.Events.One then (first, second) else (second, first)
+      in [ "You: " ++ renderPlayerStats yours, "Opponent: " ++ renderPlayerStats theirs ]
+    _ -> []
+
+renderPlayerStats : Game.Events.PlayerStats -> String
+renderPlayerStats stats =
+  let reaction = case stats.averageReactionMs of
+        Just ms -> ", average snap " ++ String.fromInt ms ++ "ms"
+        Nothing -> ""
+  in String.fromInt stats.snapsWon ++ " snaps won, "
+    ++ String.fromInt stats.falseSnaps ++ " false snaps, "
+    ++ String.fromInt stats.draws ++ " draws, "
+    ++ "biggest pile taken " ++ String.fromInt stats.largestPileTaken
+    ++ reaction
+
+tickCooldown : Int -> Table -> Table
+tickCooldown elapsedMs table = { table | drawCooldownMs = max 0 (table.drawCooldownMs - elapsedMs) }
+
 updateOffsets : Table -> Player -> (Float, Float) -> Table
 updateOffsets table player deckPosition =
   let offset = calculateOffset table.centerDeckPosition deckPosition
@@ -21,11 +21,33 @@ type alias TimedUserAction = {
 type ServerAction
   = CardDrawn { from: PlayerNumber, card: Game.Cards.Card }
   | InvalidDraw
+  | DrawTooSoon { waitMs: Int }
   | OtherPlayerResponded { player: PlayerNumber, action: TimedUserAction, isMistake: Bool }
   | PlayerTakesCenter PlayerNumber
   | PlayerWins PlayerNumber
   | GameRestarted
   | SomethingWentWrong
+  | MatchScore { roundWins: List Int, bestOf: Int }
+  | MatchWon PlayerNumber
+  | GameStats (List PlayerStats)
+  | GameState Snapshot
+
+-- Everything on the table, sent when we've fallen behind or rejoined a game
+type alias Snapshot = {
+    handSizes: List Int
+    , centerPile: List Game.Cards.Card
+    , playerTurn: PlayerNumber
+    , winner: Maybe PlayerNumber
+    }
+
+-- How one player did over a round
+type alias PlayerStats = {
+    snapsWon: Int
+    , falseSnaps: Int
+    , draws: Int
+    , averageReactionMs: Maybe Int
+    , largestPileTaken: Int
+    }
 
 -- TODO: Maybe encode rather than string interpolation? Maybe not necessary
 actionToJson : Action -> Int -> String
@@ -36,6 +58,10 @@ actionToJson action responseTime =
     NoResponse -> "{\"GameUpdate\":{\"NoResponse\":null}}"
     PlayAgain -> "{\"GameUpdate\":{\"PlayAgain\":null}}"
 
+-- Ask the server for everything on the table, when we've lost track of it
+requestStateJson : String
+requestStateJson = "\"RequestState\""
+
 
 
 updateDecoder : JSD.Decoder ServerAction
@@ -44,6 +70,11 @@ updateDecoder = JSD.oneOf [
   , JSD.field "OtherPlayerResponded" (otherPlayerRespondedDecoder)
   , JSD.field "PlayerTakesCenter" (playerEventDecoder PlayerTakesCenter)
   , JSD.field "PlayerWins" (playerEventDecoder PlayerWins)
+  , JSD.field "DrawTooSoon" (JSD.field "wait_ms" JSD.int |> JSD.map (\ms -> DrawTooSoon { waitMs = ms }))
+  , JSD.field "MatchScore" matchScoreDecoder
+  , JSD.field "MatchWon" (JSD.field "player" (playerEventDecoder MatchWon))
+  , JSD.field "GameStats" (JSD.map GameStats (JSD.list playerStatsDecoder))
+  , JSD.field "GameState" (JSD.map GameState snapshotDecoder)
   , unitTypeDecoder
   ]
 
@@ -66,6 +97,27 @@ timedActionDecoder = JSD.oneOf [
   ]
 
 
+matchScoreDecoder : JSD.Decoder ServerAction
+matchScoreDecoder = JSD.map2
+  (\roundWins -> \bestOf -> MatchScore { roundWins = roundWins, bestOf = bestOf })
+  (JSD.field "round_wins" (JSD.list JSD.int))
+  (JSD.field "best_of" JSD.int)
+
+playerStatsDecoder : JSD.Decoder PlayerStats
+playerStatsDecoder = JSD.map5 PlayerStats
+  (JSD.field "snaps_won" JSD.int)
+  (JSD.field "false_snaps" JSD.int)
+  (JSD.field "draws" JSD.int)
+  (JSD.field "average_reaction_ms" (JSD.nullable JSD.int))
+  (JSD.field "largest_pile_taken" JSD.int)
+
+snapshotDecoder : JSD.Decoder Snapshot
+snapshotDecoder = JSD.map4 Snapshot
+  (JSD.field "hand_sizes" (JSD.list JSD.int))
+  (JSD.field "center_pile" (JSD.list Game.Cards.cardDecoder))
+  (JSD.field "player_turn" playerNumberDecoder)
+  (JSD.field "winner" (JSD.nullable playerNumberDecoder))
+
 cardDrawnDecoder : JSD.Decoder ServerAction
 cardDrawnDecoder = JSD.map2
   (\player -> \card -> CardDrawn { from = player, card = card })
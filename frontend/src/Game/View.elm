@@ -36,6 +36,7 @@ viewTable table =
         (getCardDealOffset table (Just You) True)
         table.invalidDrawCount
       )
+    , drawCooldown table.drawCooldownMs
     ]
 
 
@@ -169,6 +170,15 @@ cssVariables variables =
     )
 
 
+-- Counts down until we're allowed to draw again
+drawCooldown : Int -> Html Game.Events.Action
+drawCooldown remainingMs =
+  let tenths = ceiling (toFloat remainingMs / 100)
+  in div [ class "draw-cooldown" ] (
+    if remainingMs > 0 then [ text ("Too fast! Wait " ++ String.fromFloat (toFloat tenths / 10) ++ "s") ]
+    else []
+  )
+
 eventLog : List String -> Html Game.Events.Action
 eventLog events =
   Html.Keyed.ul [ class "event-log" ]
@@ -27,13 +27,18 @@ main =
 
 type Model
   = InitialScreen { draftId: String, instructionsOpen: Bool }
-  | WaitingForPlayer { otherPlayerId: String }
+  | WaitingForPlayer { otherPlayerId: String, rejoinCode: String }
+  | InQueue { position: Int }
   | Connecting
+  | Reconnecting { rejoinCode: String, otherPlayerId: Maybe String }
   | Loading
-  | InGame Game.Data.Table
-  | EndGame Game.Data.Table { winner: Game.Data.Player, playAgainPressed: Bool, yourNumber: Game.Events.PlayerNumber }
+  | InGame Session Game.Data.Table
+  | EndGame Session Game.Data.Table { winner: Game.Data.Player, playAgainPressed: Bool, yourNumber: Game.Events.PlayerNumber, score: String, stats: List String }
   | ErrorScreen String
 
+-- What we need to get back into the game if the connection drops
+type alias Session = { rejoinCode: String }
+
 
 init : () -> ( Model, Cmd Msg )
 init _ = (
@@ -46,6 +51,8 @@ type ClientEvent
   = JoinGameIdChanged String
   | JoinGame
   | CreateGame
+  | PlayComputer
+  | QuickPlay
   | GameAction Game.Events.Action
   | DismissError
   | OpenInstructions
@@ -53,6 +60,7 @@ type ClientEvent
   -- Events triggered automatically
   | SetLastDrawTime Time.Posix
   | SubmitGameEvent Game.Events.Action Time.Posix
+  | CooldownTick
   | GotElement String (Result Dom.Error Dom.Element)
   | NoOp
 
@@ -69,6 +77,29 @@ unexpectedError = errorState "An unexpected error occurred"
 lostConnectionError : (Model, Cmd Msg)
 lostConnectionError = errorState "Lost connection to the server"
 
+serverShuttingDownError : (Model, Cmd Msg)
+serverShuttingDownError = errorState "The server is restarting, try again in a minute"
+
+tooManyPlayersError : (Model, Cmd Msg)
+tooManyPlayersError = errorState "That game has more players than this page can show"
+
+-- Why the server turned down something we sent
+protocolErrorText : String -> String
+protocolErrorText code = case code of
+  "NotYourTurn" -> "it's not your turn"
+  "GameEnded" -> "the game is over"
+  "DuplicateResponse" -> "you've already responded"
+  "GamePaused" -> "the game is paused until your opponent is back"
+  "NotAPlayer" -> "you're only watching"
+  "GameNotFound" -> "the game has closed"
+  "ServerError" -> "something went wrong on the server"
+  "Malformed" -> "the server couldn't read our message"
+  _ -> "the server didn't understand"
+
+-- How often the draw cooldown countdown updates
+cooldownTickMs : Float
+cooldownTickMs = 100
+
 updateLastDrawnTime : Cmd Msg
 updateLastDrawnTime = Task.perform (\t -> ClientEvent (SetLastDrawTime t)) Time.now
 
@@ -79,6 +110,29 @@ getDeckPosition deck = let id = Game.View.deckToId deck in
 onStartGame : Cmd Msg
 onStartGame = Cmd.batch [ updateLastDrawnTime, getDeckPosition Game.View.Center ]
 
+startGame : { yourNumber: Game.Events.PlayerNumber, rejoinCode: String } -> (Model, Cmd Msg)
+startGame data = (InGame { rejoinCode = data.rejoinCode } (Game.Data.newTable data.yourNumber), onStartGame)
+
+-- Take our seat again with the code the server gave us. The server holds the
+-- game for a while, and catches us up once we're back.
+reconnect : String -> Maybe String -> (Model, Cmd Msg)
+reconnect rejoinCode otherPlayerId = (
+  Reconnecting { rejoinCode = rejoinCode, otherPlayerId = otherPlayerId }
+  , WebSocket.connect (WebSocket.joinGameUrl rejoinCode)
+  )
+
+
+roundOver : Session -> Game.Data.Table -> Game.Events.PlayerNumber -> (Model, Cmd Msg)
+roundOver session table playerNumber = (
+  EndGame session table {
+    winner = Game.Data.playerFromNumber table playerNumber
+    , playAgainPressed = False
+    , yourNumber = table.yourNumber
+    , score = ""
+    , stats = []
+    }
+  , Cmd.none
+  )
 
 -- To submit a game action, we need to get the current time
 submitGameEvent : Game.Events.Action -> Cmd Msg
@@ -93,6 +147,8 @@ update msg model =
         JoinGameIdChanged newDraft -> (InitialScreen { state | draftId = newDraft }, Cmd.none)
         JoinGame -> (Connecting, WebSocket.connect (WebSocket.joinGameUrl state.draftId))
         CreateGame -> (Connecting, WebSocket.connect WebSocket.createGameUrl)
+        PlayComputer -> (Connecting, WebSocket.connect WebSocket.playComputerUrl)
+        QuickPlay -> (Connecting, WebSocket.connect WebSocket.quickPlayUrl)
         OpenInstructions -> (InitialScreen { state | instructionsOpen = True }, Cmd.none)
         DismissInstructions -> (InitialScreen { state | instructionsOpen = False }, Cmd.none)
         _ -> (model, Cmd.none)
@@ -104,6 +160,15 @@ update msg model =
         WebSocket.ConnectionLost _ -> errorState "Can't connect to the server"
         _ -> unexpectedError
 
+    Reconnecting info -> case msg of
+      ClientEvent _ -> (model, Cmd.none)
+      WebSocketEvent event -> case event of
+        WebSocket.ConnectionStarted _ -> case info.otherPlayerId of
+          Just otherPlayerId -> (WaitingForPlayer { otherPlayerId = otherPlayerId, rejoinCode = info.rejoinCode }, Cmd.none)
+          Nothing -> (Loading, Cmd.none)
+        WebSocket.ConnectionLost _ -> lostConnectionError
+        _ -> unexpectedError
+
     Loading -> case msg of
       ClientEvent _ -> (model, Cmd.none)
       WebSocketEvent event -> case event of
@@ -112,49 +177,98 @@ update msg model =
         WebSocket.MessageReceived wsMsg -> case (ServerMessage.decode wsMsg) of
           ServerMessage.ServerFull -> errorState "The server is full"
           ServerMessage.GameNotFound -> errorState "Couldn't find that game"
-          ServerMessage.GameStarted data -> (InGame (Game.Data.newTable data.yourNumber), onStartGame)
-          ServerMessage.GameCreated data -> (WaitingForPlayer { otherPlayerId = String.fromInt data.other_player_id }, Cmd.none)
+          ServerMessage.UserAlreadyConnected -> errorState "You're already playing that game somewhere else"
+          ServerMessage.IncompatibleVersion -> errorState "This page is out of date, reload it to keep playing"
+          ServerMessage.GameStarted data -> startGame data
+          ServerMessage.TooManyPlayers -> tooManyPlayersError
+          ServerMessage.GameCreated data -> (
+            WaitingForPlayer { otherPlayerId = data.joinCode, rejoinCode = data.rejoinCode }
+            , Cmd.none
+            )
+          ServerMessage.QueuePosition data -> (InQueue { position = data.position }, Cmd.none)
+          ServerMessage.ServerShuttingDown _ -> serverShuttingDownError
+          _ -> unexpectedError
+
+    InQueue _ -> case msg of
+      ClientEvent _ -> (model, Cmd.none)
+      WebSocketEvent event -> case event of
+        WebSocket.ConnectionLost _ -> lostConnectionError
+        WebSocket.ConnectionStarted _ -> unexpectedError
+        WebSocket.MessageReceived wsMsg -> case (ServerMessage.decode wsMsg) of
+          ServerMessage.QueuePosition data -> (InQueue { position = data.position }, Cmd.none)
+          ServerMessage.QueueTimedOut -> errorState "Nobody else turned up to play"
+          ServerMessage.ServerFull -> errorState "The server is full"
+          ServerMessage.ServerShuttingDown _ -> serverShuttingDownError
+          ServerMessage.GameStarted data -> startGame data
+          ServerMessage.TooManyPlayers -> tooManyPlayersError
           _ -> unexpectedError
 
 
     WaitingForPlayer info -> case msg of
       ClientEvent _ -> (model, Cmd.none)
       WebSocketEvent event -> case event of
-        WebSocket.ConnectionLost _ -> lostConnectionError
+        WebSocket.ConnectionLost _ -> reconnect info.rejoinCode (Just info.otherPlayerId)
         WebSocket.ConnectionStarted _ -> unexpectedError
         WebSocket.MessageReceived wsMsg -> case (ServerMessage.decode wsMsg) of
-          ServerMessage.GameStarted data -> (InGame (Game.Data.newTable data.yourNumber), onStartGame)
+          ServerMessage.GameStarted data -> startGame data
+          ServerMessage.TooManyPlayers -> tooManyPlayersError
           ServerMessage.GameDestroyed -> unexpectedError
+          -- Someone took the other seat and left again before the game started
+          ServerMessage.PlayerDisconnected -> (model, Cmd.none)
+          ServerMessage.PlayerReconnected -> (model, Cmd.none)
+          ServerMessage.ServerShuttingDown _ -> serverShuttingDownError
           _ -> unexpectedError
 
-    InGame table -> case msg of
+    InGame session table -> case msg of
       WebSocketEvent event -> case event of
-        WebSocket.ConnectionLost _ -> lostConnectionError
+        WebSocket.ConnectionLost _ -> reconnect session.rejoinCode Nothing
         WebSocket.ConnectionStarted _ -> unexpectedError
         WebSocket.MessageReceived wsMsg -> case (ServerMessage.decode wsMsg) of
           ServerMessage.GameDestroyed -> errorState "The game was destroyed"
-          ServerMessage.GameUpdate gameEvent -> let newModel = InGame (Game.Data.updateTable gameEvent table)
+          ServerMessage.PlayerDisconnected -> (
+            InGame session { table | eventLog = table.eventLog ++ [ "Opponent lost connection, waiting for them to come back" ] }
+            , Cmd.none
+            )
+          ServerMessage.PlayerReconnected -> (
+            InGame session { table | eventLog = table.eventLog ++ [ "Opponent is back" ] }
+            , Cmd.none
+            )
+          ServerMessage.ServerShuttingDown data -> let
+              seconds = String.fromInt (data.closingInMs // 1000)
+              message = "The server is restarting, this game will end in " ++ seconds ++ " seconds"
+            in (InGame session { table | eventLog = table.eventLog ++ [ message ] }, Cmd.none)
+          ServerMessage.ProtocolError data -> let
+              logged = InGame session { table | eventLog = table.eventLog ++ [ "That didn't work: " ++ protocolErrorText data.code ] }
+            in case data.code of
+              -- Drawing out of turn just shakes the cards
+              "NotYourTurn" -> (InGame session (Game.Data.updateTable Game.Events.InvalidDraw table), updateLastDrawnTime)
+              "DuplicateResponse" -> (logged, Cmd.none)
+              "GamePaused" -> (logged, Cmd.none)
+              -- Our idea of the table may be out of date, so ask for the real one
+              _ -> (logged, WebSocket.sendMessage Game.Events.requestStateJson)
+          ServerMessage.GameUpdate gameEvent -> let newModel = InGame session (Game.Data.updateTable gameEvent table)
             in case gameEvent of
               Game.Events.SomethingWentWrong -> unexpectedError
               Game.Events.CardDrawn _ -> (newModel, updateLastDrawnTime)
               Game.Events.InvalidDraw -> (newModel, updateLastDrawnTime)
+              Game.Events.DrawTooSoon _ -> (newModel, Cmd.none)
               Game.Events.PlayerTakesCenter _ -> (newModel, updateLastDrawnTime)
               Game.Events.GameRestarted -> (newModel, onStartGame)
               Game.Events.OtherPlayerResponded response -> (newModel, Cmd.none)
-              Game.Events.PlayerWins playerNumber -> (
-                EndGame table {
-                  winner = Game.Data.playerFromNumber table playerNumber
-                  , playAgainPressed = False
-                  , yourNumber = table.yourNumber
-                  }
-                , Cmd.none
-                )
+              Game.Events.MatchScore _ -> (newModel, Cmd.none)
+              Game.Events.MatchWon _ -> (newModel, Cmd.none)
+              Game.Events.GameStats _ -> (newModel, Cmd.none)
+              Game.Events.PlayerWins playerNumber -> roundOver session table playerNumber
+              Game.Events.GameState snapshot -> case snapshot.winner of
+                Just playerNumber -> roundOver session (Game.Data.updateTable gameEvent table) playerNumber
+                Nothing -> (newModel, updateLastDrawnTime)
           _ -> unexpectedError
       ClientEvent event -> case event of
         SetLastDrawTime time -> (
-          InGame { table | lastDrawnTime = Time.posixToMillis time }
+          InGame session { table | lastDrawnTime = Time.posixToMillis time }
           , Cmd.none
           )
+        CooldownTick -> (InGame session (Game.Data.tickCooldown (round cooldownTickMs) table), Cmd.none)
         GameAction action -> (model, submitGameEvent action)
         SubmitGameEvent gameEvent currentTime -> let responseTime = (Time.posixToMillis currentTime) - table.lastDrawnTime
           in (model, WebSocket.sendMessage (Game.Events.actionToJson gameEvent responseTime))
@@ -164,28 +278,52 @@ update msg model =
             Err _ -> unexpectedError
             Ok element -> let position = (element.element.x, element.element.y) in case deck of
               Game.View.Center -> (
-                InGame { table | centerDeckPosition = position }
+                InGame session { table | centerDeckPosition = position }
                 , Cmd.batch [ getDeckPosition Game.View.Yours, getDeckPosition Game.View.Opponents ]
                 )
-              Game.View.Yours -> (InGame (Game.Data.updateOffsets table Game.Data.You position), Cmd.none)
-              Game.View.Opponents -> (InGame (Game.Data.updateOffsets table Game.Data.Opponent position), Cmd.none)
+              Game.View.Yours -> (InGame session (Game.Data.updateOffsets table Game.Data.You position), Cmd.none)
+              Game.View.Opponents -> (InGame session (Game.Data.updateOffsets table Game.Data.Opponent position), Cmd.none)
         _ -> (model, Cmd.none)
 
-    EndGame table info -> case msg of
+    EndGame session table info -> case msg of
       ClientEvent event -> case event of
         SubmitGameEvent gameEvent _ -> (model, WebSocket.sendMessage (Game.Events.actionToJson gameEvent 0))
         GameAction action -> case action of
-          Game.Events.PlayAgain -> (EndGame table { info | playAgainPressed = True }, submitGameEvent action)
+          Game.Events.PlayAgain -> (EndGame session table { info | playAgainPressed = True }, submitGameEvent action)
           _ -> (model, Cmd.none)
         _ -> (model, Cmd.none)
       WebSocketEvent event -> case event of
-        WebSocket.ConnectionLost _ -> lostConnectionError
+        WebSocket.ConnectionLost _ -> reconnect session.rejoinCode Nothing
         WebSocket.ConnectionStarted _ -> unexpectedError
         WebSocket.MessageReceived wsMsg -> case (ServerMessage.decode wsMsg) of
           ServerMessage.GameDestroyed -> errorState "The game was destroyed"
+          ServerMessage.PlayerDisconnected -> (
+            EndGame session { table | eventLog = table.eventLog ++ [ "Opponent lost connection, waiting for them to come back" ] } info
+            , Cmd.none
+            )
+          ServerMessage.PlayerReconnected -> (
+            EndGame session { table | eventLog = table.eventLog ++ [ "Opponent is back" ] } info
+            , Cmd.none
+            )
           ServerMessage.GameUpdate gameEvent -> case gameEvent of
-              Game.Events.GameRestarted -> (InGame (Game.Data.newTable info.yourNumber), onStartGame)
+              Game.Events.GameRestarted -> (InGame session (Game.Data.newTable info.yourNumber), onStartGame)
+              Game.Events.MatchScore score -> (
+                EndGame session table { info | score = Game.Data.renderScore table score.roundWins score.bestOf }
+                , Cmd.none
+                )
+              Game.Events.MatchWon playerNumber -> let
+                  matchResult = case Game.Data.playerFromNumber table playerNumber of
+                    Game.Data.You -> "You win the match!"
+                    Game.Data.Opponent -> "Opponent wins the match"
+                in (EndGame session table { info | score = info.score ++ ". " ++ matchResult }, Cmd.none)
+              Game.Events.GameStats stats -> (
+                EndGame session table { info | stats = Game.Data.renderStats table stats }
+                , Cmd.none
+                )
+              Game.Events.GameState _ -> (model, Cmd.none)
               _ -> unexpectedError
+          ServerMessage.ServerShuttingDown _ -> (model, Cmd.none)
+          ServerMessage.ProtocolError _ -> (model, Cmd.none)
           _ -> unexpectedError
 
     ErrorScreen _ -> case msg of
@@ -198,10 +336,15 @@ update msg model =
 -- SUBSCRIPTIONS
 
 subscriptions : Model -> Sub Msg
-subscriptions _ = Sub.batch [
+subscriptions model = Sub.batch [
   WebSocket.messageReceived (\m -> WebSocketEvent (WebSocket.MessageReceived m))
   , WebSocket.connectionLost (\_ -> WebSocketEvent (WebSocket.ConnectionLost ()))
   , WebSocket.connectionStarted (\_ -> WebSocketEvent (WebSocket.ConnectionStarted ()))
+  , case model of
+      InGame _ table -> if table.drawCooldownMs > 0
+        then Time.every cooldownTickMs (\_ -> ClientEvent CooldownTick)
+        else Sub.none
+      _ -> Sub.none
   ]
 
 
@@ -227,6 +370,8 @@ viewInitialScreen draftId modalOpen =
   div [ class "non-game-container" ]
     ([
        button [ onClick CreateGame ] [ text "Start a new game" ]
+       , button [ onClick PlayComputer ] [ text "Play the computer" ]
+       , button [ onClick QuickPlay ] [ text "Play a stranger" ]
        , div [] [ text "or" ]
        , div [ class "join-game" ] [
          input
@@ -272,8 +417,8 @@ displayError message =
 
 
 
-endGame : Game.Data.Table -> Game.Data.Player -> Bool -> List (Html ClientEvent)
-endGame table winner playAgainPressed =
+endGame : Game.Data.Table -> Game.Data.Player -> Bool -> String -> List String -> List (Html ClientEvent)
+endGame table winner playAgainPressed score stats =
   let
     message = case winner of
       Game.Data.You -> "You win! 🎉"
@@ -282,6 +427,8 @@ endGame table winner playAgainPressed =
     (Game.View.viewTable table) |> Html.map (\_ -> NoOp)
     , div [ class "modal" ] [
       text message
+      , div [] [ text score ]
+      , div [ class "stats" ] (stats |> List.map (\line -> p [] [ text line ]))
       , button [ disabled playAgainPressed, onClick (GameAction Game.Events.PlayAgain) ] [ text "Play again" ]
       ]
     ]
@@ -292,9 +439,11 @@ view model = appContainer (
   case model of
     InitialScreen state -> [ viewInitialScreen state.draftId state.instructionsOpen ]
     Connecting -> [ displayMessage [ "Connecting...", "(This can sometimes take a minute as the service spins down when inactive)" ] ]
+    Reconnecting _ -> [ displayMessage [ "Lost connection to the server, reconnecting..." ] ]
     Loading -> [ displayMessage [ "Loading" ] ]
     WaitingForPlayer data -> [ displayMessage [ "Tell a friend to join using the following code: " ++ data.otherPlayerId ] ]
+    InQueue data -> [ displayMessage [ "Looking for someone to play with...", "You're number " ++ String.fromInt data.position ++ " in the queue" ] ]
     ErrorScreen message -> [ displayError message ]
-    InGame table -> [ (Game.View.viewTable table) |> Html.map GameAction ]
-    EndGame table info -> endGame table info.winner info.playAgainPressed
+    InGame _ table -> [ (Game.View.viewTable table) |> Html.map GameAction ]
+    EndGame _ table info -> endGame table info.winner info.playAgainPressed info.score info.stats
   )
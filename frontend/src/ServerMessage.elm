@@ -6,12 +6,20 @@ import Json.Decode as JSD
 import Game.Events
 
 type ServerMessage
-  = GameCreated { other_player_id: Int }
+  = GameCreated { joinCode: String, rejoinCode: String }
   | GameDestroyed
   | ServerFull
   | UserAlreadyConnected
   | GameNotFound
-  | GameStarted { yourNumber: Game.Events.PlayerNumber }
+  | GameStarted { yourNumber: Game.Events.PlayerNumber, rejoinCode: String }
+  | TooManyPlayers
+  | PlayerDisconnected
+  | PlayerReconnected
+  | QueuePosition { position: Int }
+  | QueueTimedOut
+  | ServerShuttingDown { closingInMs: Int }
+  | ProtocolError { code: String }
+  | IncompatibleVersion
   | GameUpdate Game.Events.ServerAction
   | UnknownMessage
 
@@ -27,6 +35,12 @@ serverMessageDecoder = JSD.oneOf [
   gameCreatedDecoder
   , gameStartedDecoder
   , gameUpdateDecoder
+  , JSD.field "PlayerDisconnected" (JSD.succeed PlayerDisconnected)
+  , JSD.field "PlayerReconnected" (JSD.succeed PlayerReconnected)
+  , queuePositionDecoder
+  , serverShuttingDownDecoder
+  , protocolErrorDecoder
+  , JSD.field "IncompatibleVersion" (JSD.succeed IncompatibleVersion)
   , unitTypeDecoder
   ]
 
@@ -37,18 +51,41 @@ unitTypeDecoder = JSD.string |> (
       "GameDestroyed" -> GameDestroyed
       "UserAlreadyConnected" -> UserAlreadyConnected
       "GameNotFound" -> GameNotFound
+      "QueueTimedOut" -> QueueTimedOut
       _ -> UnknownMessage
     )
   )
 
 gameCreatedDecoder : JSD.Decoder ServerMessage
-gameCreatedDecoder = JSD.field "GameCreated" (JSD.field "other_player_id" JSD.int)
-  |> (JSD.map (\id -> GameCreated { other_player_id = id }))
+gameCreatedDecoder = JSD.field "GameCreated" (JSD.map2
+  (\code rejoinCode -> GameCreated { joinCode = code, rejoinCode = rejoinCode })
+  (JSD.field "join_codes" (JSD.index 0 JSD.string))
+  (JSD.field "rejoin_code" JSD.string)
+  )
 
 
+-- The table only has room for two players, so bigger games are turned down
 gameStartedDecoder : JSD.Decoder ServerMessage
-gameStartedDecoder = JSD.field "GameStarted" (JSD.field "your_number" Game.Events.playerNumberDecoder)
-  |> (JSD.map (\num -> GameStarted { yourNumber = num }))
+gameStartedDecoder = JSD.field "GameStarted" (JSD.field "num_players" JSD.int |> JSD.andThen (
+  \numPlayers -> if numPlayers == 2
+    then JSD.map2
+      (\num rejoinCode -> GameStarted { yourNumber = num, rejoinCode = rejoinCode })
+      (JSD.field "your_number" Game.Events.playerNumberDecoder)
+      (JSD.field "rejoin_code" JSD.string)
+    else JSD.succeed TooManyPlayers
+  ))
+
+queuePositionDecoder : JSD.Decoder ServerMessage
+queuePositionDecoder = JSD.field "QueuePosition" (JSD.field "position" JSD.int)
+  |> (JSD.map (\position -> QueuePosition { position = position }))
+
+serverShuttingDownDecoder : JSD.Decoder ServerMessage
+serverShuttingDownDecoder = JSD.field "ServerShuttingDown" (JSD.field "closing_in_ms" JSD.int)
+  |> (JSD.map (\closingInMs -> ServerShuttingDown { closingInMs = closingInMs }))
+
+protocolErrorDecoder : JSD.Decoder ServerMessage
+protocolErrorDecoder = JSD.field "ProtocolError" (JSD.field "code" JSD.string)
+  |> (JSD.map (\code -> ProtocolError { code = code }))
 
 gameUpdateDecoder : JSD.Decoder ServerMessage
 gameUpdateDecoder = JSD.field "GameUpdate" Game.Events.updateDecoder |> JSD.map GameUpdate
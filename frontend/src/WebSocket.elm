@@ -18,8 +18,23 @@ type Event =
 baseUrl : String
 baseUrl = "929b8e9b3748f2e04edf"
 
+-- Version of the server's messages this frontend understands. Keep in step
+-- with PROTOCOL_VERSION in the backend's message module.
+protocolVersion : Int
+protocolVersion = 2
+
+protocolQuery : String
+protocolQuery = "?protocol=" ++ String.fromInt protocolVersion
+
 joinGameUrl : String -> String
-joinGameUrl id = baseUrl ++ "/join/" ++ id
+joinGameUrl id = baseUrl ++ "/join/" ++ id ++ protocolQuery
 
+-- The table only has room for two players
 createGameUrl : String
-createGameUrl = baseUrl ++ "/create"
+createGameUrl = baseUrl ++ "/create" ++ protocolQuery ++ "&players=2"
+
+playComputerUrl : String
+playComputerUrl = createGameUrl ++ "&bot=medium"
+
+quickPlayUrl : String
+quickPlayUrl = baseUrl ++ "/quickplay" ++ protocolQuery